@@ -0,0 +1,79 @@
+//! Starts the profiler and exposes its status on a tiny HTTP endpoint, for
+//! wiring into a service's health checks.
+//!
+//! ```sh
+//! cargo run --example health_endpoint -- <project-id> <service> <version> [addr]
+//! curl -i http://127.0.0.1:8080/profiler
+//! ```
+//!
+//! `GET /profiler` answers `200 OK` with the JSON serialized
+//! `ProfilerStatus` while the profiler is running or paused, and
+//! `503 Service Unavailable` once it has stopped.
+
+use cloud_profiler_rust::{ProfilerBuilder, ProfilerHandle, ProfilerState};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+
+fn respond(stream: TcpStream, handle: &ProfilerHandle) -> std::io::Result<()> {
+    let mut request_line = String::new();
+    BufReader::new(&stream).read_line(&mut request_line)?;
+    let (status_line, body) = if request_line.starts_with("GET /profiler ") {
+        let status = handle.status();
+        let status_line = match status.state {
+            ProfilerState::Running | ProfilerState::Paused => "200 OK",
+            ProfilerState::Stopped => "503 Service Unavailable",
+        };
+        let body = serde_json::to_string(&status).unwrap_or_default();
+        (status_line, body)
+    } else {
+        ("404 Not Found", String::new())
+    };
+    let mut stream = stream;
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status_line,
+        body.len(),
+        body
+    )
+}
+
+fn serve(listener: TcpListener, handle: ProfilerHandle) {
+    for stream in listener.incoming() {
+        let result = stream.and_then(|stream| respond(stream, &handle));
+        if let Err(e) = result {
+            eprintln!("Failed to answer request: {}", e);
+        }
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let mut args = std::env::args().skip(1);
+    let (Some(project_id), Some(service), Some(version)) = (args.next(), args.next(), args.next())
+    else {
+        eprintln!("usage: health_endpoint <project-id> <service> <version> [addr]");
+        std::process::exit(2);
+    };
+    let addr = args.next().unwrap_or_else(|| "127.0.0.1:8080".to_string());
+
+    let handle = ProfilerBuilder::new()
+        .project_id(project_id)
+        .service(service)
+        .version(version)
+        .start()
+        .await
+        .expect("invalid profiler configuration");
+
+    let listener = TcpListener::bind(&addr).expect("failed to bind the health endpoint");
+    println!("Serving profiler status on http://{}/profiler", addr);
+    let server = {
+        let handle = handle.clone();
+        std::thread::spawn(move || serve(listener, handle))
+    };
+
+    handle.join().await;
+    println!("Profiler stopped, last error: {:?}", handle.last_error());
+    // Keep reporting the stopped state until the process is killed
+    let _ = server.join();
+}
@@ -0,0 +1,57 @@
+//! Profiles a CPU-busy loop and uploads a single profile to a real project.
+//!
+//! ```sh
+//! cargo run --example smoke_test -- <project-id> <service> <version>
+//! ```
+//!
+//! Credentials are picked up from `GOOGLE_APPLICATION_CREDENTIALS` or the
+//! GCE metadata server.
+
+use std::hint::black_box;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+fn busy_loop(done: &AtomicBool) -> u64 {
+    let mut x = 0u64;
+    while !done.load(Ordering::Relaxed) {
+        for i in 0..1_000_000u64 {
+            x = black_box(x.wrapping_mul(31).wrapping_add(i));
+        }
+    }
+    x
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let [project_id, service, version] = match <[String; 3]>::try_from(args) {
+        Ok(args) => args,
+        Err(_) => {
+            eprintln!("usage: smoke_test <project-id> <service> <version>");
+            std::process::exit(2);
+        }
+    };
+
+    let done = Arc::new(AtomicBool::new(false));
+    let busy = {
+        let done = done.clone();
+        std::thread::spawn(move || busy_loop(&done))
+    };
+
+    let result = cloud_profiler_rust::ProfilerBuilder::new()
+        .project_id(project_id)
+        .service(service)
+        .version(version)
+        .once()
+        .await;
+
+    done.store(true, Ordering::Relaxed);
+    let _ = busy.join();
+    match result {
+        Ok(name) => println!("Uploaded profile {}", name),
+        Err(e) => {
+            eprintln!("Failed to upload profile: {} ({:?})", e, e);
+            std::process::exit(1);
+        }
+    }
+}
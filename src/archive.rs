@@ -0,0 +1,46 @@
+//! Local archive of the uploaded profiles, kept alongside the upload to GCP,
+//! see `ProfilerBuilder::archive_dir`.
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Suffix of archived profiles: gzip compressed pprof, as read by `pprof`.
+const SUFFIX: &str = ".pb.gz";
+
+/// Writes the gzip compressed pprof `content` of the profile `id` to
+/// `<dir>/<id>.pb.gz`, then removes the archived profiles older than
+/// `retention`. Returns the path written.
+pub(crate) fn write_profile(
+    dir: &Path,
+    id: &str,
+    content: &[u8],
+    retention: Duration,
+) -> std::io::Result<PathBuf> {
+    std::fs::create_dir_all(dir)?;
+    let path = dir.join(format!("{}{}", id, SUFFIX));
+    std::fs::write(&path, content)?;
+    prune(dir, retention)?;
+    Ok(path)
+}
+
+/// Removes the archived profiles last modified more than `retention` ago.
+/// Files that vanish or can't be removed meanwhile are skipped, they are
+/// retried on the next prune.
+fn prune(dir: &Path, retention: Duration) -> std::io::Result<()> {
+    let now = SystemTime::now();
+    for entry in std::fs::read_dir(dir)?.flatten() {
+        if !entry.file_name().to_string_lossy().ends_with(SUFFIX) {
+            continue;
+        }
+        let Ok(modified) = entry.metadata().and_then(|m| m.modified()) else {
+            continue;
+        };
+        if now
+            .duration_since(modified)
+            .is_ok_and(|age| age > retention)
+        {
+            let _ = std::fs::remove_file(entry.path());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,170 @@
+use crate::GcpCloudProfilingError;
+use google_cloud_auth::credentials::CredentialsFile;
+use google_cloud_token::{TokenSource, TokenSourceProvider};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+pub(crate) const DEFAULT_METADATA_CONCURRENCY: usize = 1;
+
+/// Process-wide limit on concurrent metadata server / token requests, shared
+/// by every profiling loop so they can't collectively trip its rate limits.
+pub(crate) static METADATA_CONCURRENCY: crate::ConcurrencyLimit =
+    crate::ConcurrencyLimit::new("metadata_concurrency", DEFAULT_METADATA_CONCURRENCY);
+
+const SCOPES: [&str; 3] = [
+    "https://www.googleapis.com/auth/cloud-platform",
+    "https://www.googleapis.com/auth/monitoring",
+    "https://www.googleapis.com/auth/monitoring.write",
+];
+
+/// Holds on to the token source across profiling cycles so that the
+/// provider (which hits the metadata server or reads credential files)
+/// is only constructed once, and rebuilt only after it fails to
+/// produce a token.
+///
+/// Credentials are resolved in the following order:
+///
+/// 1. The credentials file explicitly configured on the builder.
+/// 2. `GOOGLE_APPLICATION_CREDENTIALS` (or `GOOGLE_APPLICATION_CREDENTIALS_JSON`).
+/// 3. The GCE metadata server.
+pub(crate) struct AuthTokenProvider {
+    credentials_file: Option<PathBuf>,
+    token_source: Option<Arc<dyn TokenSource>>,
+}
+
+impl AuthTokenProvider {
+    pub(crate) fn new(credentials_file: Option<PathBuf>) -> Self {
+        AuthTokenProvider {
+            credentials_file,
+            token_source: None,
+        }
+    }
+
+    pub(crate) async fn get_auth_token(&mut self) -> Result<String, GcpCloudProfilingError> {
+        let _permit = METADATA_CONCURRENCY.acquire().await;
+        let token_source = match &self.token_source {
+            Some(token_source) => token_source.clone(),
+            None => {
+                let token_source = build_token_source(&self.credentials_file).await?;
+                self.token_source = Some(token_source.clone());
+                token_source
+            }
+        };
+        match token_source.token().await {
+            Ok(token) => Ok(token.trim_start_matches("Bearer ").to_string()),
+            Err(e) => {
+                // The token source refreshes tokens on its own, so a failure
+                // here is treated as a hard failure and the provider is
+                // rebuilt on the next attempt.
+                self.token_source = None;
+                Err(GcpCloudProfilingError::FailedToGetAuthToken(e.to_string()))
+            }
+        }
+    }
+}
+
+async fn build_token_source(
+    credentials_file: &Option<PathBuf>,
+) -> Result<Arc<dyn TokenSource>, GcpCloudProfilingError> {
+    let config = google_cloud_auth::project::Config {
+        audience: None,
+        scopes: Some(&SCOPES),
+        sub: None,
+    };
+    let tsp = match credentials_file {
+        Some(path) => {
+            let credentials = CredentialsFile::new_from_file(path.to_string_lossy().to_string())
+                .await
+                .map_err(|e| GcpCloudProfilingError::FailedToGetAuthToken(e.to_string()))?;
+            google_cloud_auth::token::DefaultTokenSourceProvider::new_with_credentials(
+                config,
+                Box::new(credentials),
+            )
+            .await
+        }
+        None => google_cloud_auth::token::DefaultTokenSourceProvider::new(config).await,
+    }
+    .map_err(|e| GcpCloudProfilingError::FailedToGetAuthToken(e.to_string()))?;
+    Ok(tsp.token_source())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Answers the first request to the returned local URL with an access
+    /// token, standing in for Google's OAuth token endpoint.
+    async fn token_endpoint(access_token: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/token", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            // Read the headers and then the body, so the client isn't cut off
+            // while still sending
+            let body_end = loop {
+                let n = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request);
+                if let Some(headers_end) = text.find("\r\n\r\n") {
+                    let length = text[..headers_end]
+                        .lines()
+                        .find_map(|line| {
+                            let (name, value) = line.split_once(':')?;
+                            name.eq_ignore_ascii_case("content-length")
+                                .then(|| value.trim().parse::<usize>().ok())?
+                        })
+                        .unwrap_or(0);
+                    break headers_end + 4 + length;
+                }
+            };
+            while request.len() < body_end {
+                let n = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            let body = format!(
+                r#"{{"access_token":"{}","token_type":"Bearer","expires_in":3600}}"#,
+                access_token
+            );
+            let response = format!(
+                "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn credentials_file_provides_the_token() {
+        let token_uri = token_endpoint("token-from-file").await;
+        let path = std::env::temp_dir().join(format!(
+            "cloud-profiler-credentials-{}.json",
+            std::process::id()
+        ));
+        let credentials = format!(
+            r#"{{"type":"authorized_user","client_id":"id","client_secret":"secret","refresh_token":"refresh","token_uri":"{}"}}"#,
+            token_uri
+        );
+        std::fs::write(&path, credentials).unwrap();
+        let token = AuthTokenProvider::new(Some(path.clone()))
+            .get_auth_token()
+            .await;
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(token.unwrap(), "token-from-file");
+    }
+
+    #[tokio::test]
+    async fn missing_credentials_file_fails_without_falling_back() {
+        let path = PathBuf::from("/nonexistent/cloud-profiler-credentials.json");
+        let token = AuthTokenProvider::new(Some(path)).get_auth_token().await;
+        assert!(matches!(
+            token,
+            Err(GcpCloudProfilingError::FailedToGetAuthToken(_))
+        ));
+    }
+}
@@ -1,10 +1,24 @@
 use rand::Rng;
+use std::time::Duration;
+use tokio::time::Instant;
 
 // Implementation from python implementation: https://github.com/GoogleCloudPlatform/cloud-profiler-python/blob/main/googlecloudprofiler/backoff.py
 // Skips error based backoff - just backsoff no matter what
 
+/// How the backoff envelope recovers after a successful cycle.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BackoffReset {
+    /// Snap back to the minimum envelope.
+    #[default]
+    Reset,
+    /// Divide the envelope by the multiplier, down to the minimum, so
+    /// intermittent failures don't oscillate between short and long waits.
+    Decay,
+}
+
 #[derive(Debug)]
 pub struct Backoff {
+    min_envelope_sec: f64,
     max_envelope_sec: f64,
     multiplier: f64,
     current_envelope_sec: f64,
@@ -13,6 +27,7 @@ pub struct Backoff {
 impl Backoff {
     pub fn new(min_envelope_sec: f64, max_envelope_sec: f64, multiplier: f64) -> Self {
         Backoff {
+            min_envelope_sec,
             max_envelope_sec,
             multiplier,
             current_envelope_sec: min_envelope_sec,
@@ -29,4 +44,144 @@ impl Backoff {
 
         duration
     }
+
+    /// Shrinks the envelope after a success according to `strategy`.
+    pub fn on_success(&mut self, strategy: BackoffReset) {
+        match strategy {
+            BackoffReset::Reset => self.reset(),
+            BackoffReset::Decay => self.decay(),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.current_envelope_sec = self.min_envelope_sec;
+    }
+
+    pub fn decay(&mut self) {
+        self.current_envelope_sec = self
+            .min_envelope_sec
+            .max(self.current_envelope_sec / self.multiplier);
+    }
+}
+
+/// Minimum period between cycles once `failures` consecutive cycles have
+/// failed: `base` at `threshold` failures, doubling with every further
+/// failure up to `max`. `None` below the threshold or when `threshold` is 0.
+pub fn degraded_period(
+    failures: u32,
+    threshold: u32,
+    base: Duration,
+    max: Duration,
+) -> Option<Duration> {
+    if threshold == 0 || failures < threshold {
+        return None;
+    }
+    let doublings = (failures - threshold).min(31);
+    Some(base.saturating_mul(1 << doublings).min(max))
+}
+
+/// Token bucket bounding the number of retries over time: holds up to
+/// `max_retries` tokens, refilled continuously over `window`.
+#[derive(Debug)]
+pub struct RetryBudget {
+    capacity: f64,
+    tokens: f64,
+    refill_per_sec: f64,
+    last_refill: Instant,
+}
+
+impl RetryBudget {
+    pub fn new(max_retries: u32, window: Duration) -> Self {
+        let capacity = f64::from(max_retries.max(1));
+        RetryBudget {
+            capacity,
+            tokens: capacity,
+            refill_per_sec: capacity / window.as_secs_f64().max(1.0),
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Takes a token for the next retry, returning how long to wait until
+    /// that token has refilled (zero while the budget isn't exhausted).
+    pub fn acquire(&mut self) -> Duration {
+        let now = Instant::now();
+        let refilled = (now - self.last_refill).as_secs_f64() * self.refill_per_sec;
+        self.tokens = self.capacity.min(self.tokens + refilled) - 1.0;
+        self.last_refill = now;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.refill_per_sec)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decay_shrinks_the_envelope_by_the_multiplier_down_to_the_minimum() {
+        let mut backoff = Backoff::new(60.0, 3600.0, 2.0);
+        for _ in 0..3 {
+            backoff.next_backoff();
+        }
+        assert_eq!(backoff.current_envelope_sec, 480.0);
+
+        let mut envelopes = Vec::new();
+        for _ in 0..4 {
+            backoff.on_success(BackoffReset::Decay);
+            envelopes.push(backoff.current_envelope_sec);
+        }
+        assert_eq!(envelopes, [240.0, 120.0, 60.0, 60.0]);
+    }
+
+    #[test]
+    fn reset_snaps_the_envelope_back_to_the_minimum() {
+        let mut backoff = Backoff::new(60.0, 3600.0, 2.0);
+        for _ in 0..10 {
+            assert!(backoff.next_backoff() < backoff.max_envelope_sec);
+        }
+        assert_eq!(backoff.current_envelope_sec, 3600.0);
+        backoff.on_success(BackoffReset::Reset);
+        assert_eq!(backoff.current_envelope_sec, 60.0);
+    }
+
+    #[test]
+    fn degraded_period_doubles_from_the_threshold_up_to_max() {
+        let base = Duration::from_secs(60);
+        let max = Duration::from_secs(300);
+        let periods: Vec<_> = (2..7)
+            .map(|failures| degraded_period(failures, 3, base, max))
+            .collect();
+        assert_eq!(
+            periods,
+            [
+                None,
+                Some(Duration::from_secs(60)),
+                Some(Duration::from_secs(120)),
+                Some(Duration::from_secs(240)),
+                Some(Duration::from_secs(300)),
+            ]
+        );
+        assert_eq!(degraded_period(100, 0, base, max), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retry_budget_refills_over_the_window() {
+        let mut budget = RetryBudget::new(2, Duration::from_secs(10));
+        assert_eq!(budget.acquire(), Duration::ZERO);
+        assert_eq!(budget.acquire(), Duration::ZERO);
+        // Exhausted, one token refills every 5s
+        assert_eq!(budget.acquire(), Duration::from_secs(5));
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(budget.acquire(), Duration::ZERO);
+
+        // Never refills beyond its capacity
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert_eq!(budget.acquire(), Duration::ZERO);
+        assert_eq!(budget.acquire(), Duration::ZERO);
+        assert_eq!(budget.acquire(), Duration::from_secs(5));
+    }
 }
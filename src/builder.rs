@@ -0,0 +1,969 @@
+use crate::backoff::BackoffReset;
+use crate::config::{
+    is_empty, LabelPrecedence, Platform, ProfilerConfigError, ProfilerTarget, DEFAULT_MAX_LABELS,
+};
+use crate::CloudProfilerConfiguration;
+use crate::ProfilerHandle;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Environment variables consulted by `ProfilerBuilder::from_env`. These
+/// mirror the variables read by the officially supported GCP profiler agents.
+#[derive(Deserialize, Default)]
+struct EnvConfiguration {
+    google_cloud_project: Option<String>,
+    gcloud_project: Option<String>,
+    gae_service: Option<String>,
+    gae_version: Option<String>,
+    k_service: Option<String>,
+    k_revision: Option<String>,
+}
+
+/// Returns an uncompressed, serialized pprof `profile.proto` containing a
+/// heap profile, see `ProfilerBuilder::heap_profile`.
+pub type HeapProfileProvider = Arc<dyn Fn() -> Result<Vec<u8>, String> + Send + Sync>;
+
+/// Consecutive failed cycles after which the cadence slows down unless
+/// overridden by `ProfilerBuilder::degrade_after`.
+pub(crate) const DEFAULT_DEGRADE_AFTER: u32 = 5;
+
+/// Number of cycle outcomes kept for `ProfilerHandle::recent_outcomes`
+/// unless overridden by `ProfilerBuilder::recent_outcomes`.
+pub(crate) const DEFAULT_RECENT_OUTCOMES: usize = 16;
+
+/// Maximum number of concurrent uploads in the process unless overridden by
+/// `ProfilerBuilder::upload_concurrency`.
+pub(crate) const DEFAULT_UPLOAD_CONCURRENCY: usize = 2;
+
+/// Initial capacity of the buffer profiles are compressed into unless
+/// overridden by `ProfilerBuilder::gzip_capacity`.
+pub(crate) const DEFAULT_GZIP_CAPACITY: usize = 64 * 1024;
+
+/// Pause between successful cycles unless overridden by
+/// `ProfilerBuilder::cycle_jitter`.
+pub(crate) const DEFAULT_CYCLE_JITTER: (Duration, Duration) =
+    (Duration::ZERO, Duration::from_secs(5));
+
+/// Returns the bearer token sent with every API call, see
+/// `ProfilerBuilder::token_provider`.
+pub type TokenProvider = Arc<dyn Fn() -> Result<String, String> + Send + Sync>;
+
+/// Adjusts a `CreateProfileRequest` before it is sent, see
+/// `ProfilerBuilder::customize_create_request`.
+pub type CreateRequestHook = Arc<dyn Fn(&mut crate::CreateProfileRequest) + Send + Sync>;
+
+/// Decides whether a collected profile is uploaded, see
+/// `ProfilerBuilder::should_upload`.
+pub type UploadFilter =
+    Arc<dyn Fn(&crate::ProfileType, &crate::Profile, &[u8]) -> bool + Send + Sync>;
+
+/// Receives the events of the profiling loop, see
+/// `ProfilerBuilder::on_event`.
+pub type EventHook = Arc<dyn Fn(&crate::ProfilerEvent) + Send + Sync>;
+
+/// Returns the labels attached to an individual uploaded profile, see
+/// `ProfilerBuilder::profile_annotations`.
+pub type AnnotationProvider = Arc<dyn Fn() -> HashMap<String, String> + Send + Sync>;
+
+/// Builder for configuring and starting the GCP profiler.
+///
+/// # Example
+///
+/// ```no_run
+/// # async fn run() {
+/// cloud_profiler_rust::ProfilerBuilder::from_env()
+///     .service("my-service")
+///     .should_start(|| true)
+///     .start()
+///     .await
+///     .expect("invalid profiler configuration");
+/// # }
+/// ```
+pub struct ProfilerBuilder {
+    pub(crate) target: ProfilerTarget,
+    pub(crate) deployment: Option<crate::Deployment>,
+    pub(crate) should_start: Arc<dyn Fn() -> bool + Send + Sync>,
+    pub(crate) get_configuration: Arc<dyn Fn() -> CloudProfilerConfiguration + Send + Sync>,
+    pub(crate) startup_jitter: Option<Duration>,
+    pub(crate) cycle_jitter: (Duration, Duration),
+    pub(crate) align_cycles: Option<Duration>,
+    pub(crate) labels: HashMap<String, String>,
+    pub(crate) auto_labels: bool,
+    pub(crate) label_precedence: LabelPrecedence,
+    pub(crate) metadata_refresh: Option<Duration>,
+    pub(crate) process_start_label: bool,
+    pub(crate) credentials_file: Option<PathBuf>,
+    pub(crate) token_provider: Option<TokenProvider>,
+    pub(crate) healthy_within: Option<Duration>,
+    pub(crate) metadata_concurrency: Option<usize>,
+    pub(crate) upload_concurrency: Option<usize>,
+    pub(crate) log_target: Option<String>,
+    pub(crate) heap_profile: Option<HeapProfileProvider>,
+    pub(crate) max_lifetime: Option<Duration>,
+    pub(crate) retry_budget: Option<(u32, Duration)>,
+    pub(crate) backoff_reset: BackoffReset,
+    pub(crate) degrade_after: u32,
+    pub(crate) http2_only: bool,
+    pub(crate) max_outstanding: usize,
+    pub(crate) recent_outcomes: usize,
+    pub(crate) gzip_capacity: usize,
+    pub(crate) max_upload_delay: Option<Duration>,
+    pub(crate) customize_create_request: Option<CreateRequestHook>,
+    pub(crate) max_labels: usize,
+    pub(crate) reject_excess_labels: bool,
+    pub(crate) reject_unsupported_profile_types: bool,
+    pub(crate) override_duration: Option<Duration>,
+    pub(crate) pool_idle_timeout: Option<Duration>,
+    pub(crate) pool_max_idle_per_host: Option<usize>,
+    pub(crate) client_max_age: Option<Duration>,
+    pub(crate) connector_attempts: u32,
+    pub(crate) skip_gce_check: bool,
+    pub(crate) platform: Option<Platform>,
+    pub(crate) should_upload: Option<UploadFilter>,
+    pub(crate) profile_annotations: Option<AnnotationProvider>,
+    pub(crate) on_event: Option<EventHook>,
+    pub(crate) archive: Option<(PathBuf, Duration)>,
+    pub(crate) enabled: bool,
+    #[cfg(feature = "speedscope")]
+    pub(crate) speedscope_dir: Option<PathBuf>,
+    #[cfg(feature = "dedicated-thread")]
+    pub(crate) dedicated_thread: bool,
+    #[cfg(feature = "dedicated-thread")]
+    pub(crate) cpu_affinity: Option<usize>,
+}
+
+impl Default for ProfilerBuilder {
+    fn default() -> Self {
+        ProfilerBuilder {
+            target: ProfilerTarget::default(),
+            deployment: None,
+            should_start: Arc::new(|| true),
+            get_configuration: Arc::new(CloudProfilerConfiguration::default),
+            startup_jitter: None,
+            cycle_jitter: DEFAULT_CYCLE_JITTER,
+            align_cycles: None,
+            labels: HashMap::new(),
+            auto_labels: true,
+            label_precedence: LabelPrecedence::UserWins,
+            metadata_refresh: None,
+            process_start_label: false,
+            credentials_file: None,
+            token_provider: None,
+            healthy_within: None,
+            metadata_concurrency: None,
+            upload_concurrency: None,
+            log_target: None,
+            heap_profile: None,
+            max_lifetime: None,
+            retry_budget: None,
+            backoff_reset: BackoffReset::Reset,
+            degrade_after: DEFAULT_DEGRADE_AFTER,
+            http2_only: false,
+            max_outstanding: 1,
+            recent_outcomes: DEFAULT_RECENT_OUTCOMES,
+            gzip_capacity: DEFAULT_GZIP_CAPACITY,
+            max_upload_delay: None,
+            customize_create_request: None,
+            max_labels: DEFAULT_MAX_LABELS,
+            reject_excess_labels: false,
+            reject_unsupported_profile_types: false,
+            override_duration: None,
+            pool_idle_timeout: None,
+            pool_max_idle_per_host: None,
+            client_max_age: None,
+            connector_attempts: 3,
+            skip_gce_check: false,
+            platform: None,
+            should_upload: None,
+            profile_annotations: None,
+            on_event: None,
+            archive: None,
+            enabled: true,
+            #[cfg(feature = "speedscope")]
+            speedscope_dir: None,
+            #[cfg(feature = "dedicated-thread")]
+            dedicated_thread: false,
+            #[cfg(feature = "dedicated-thread")]
+            cpu_affinity: None,
+        }
+    }
+}
+
+impl ProfilerBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a builder with `project_id`, `service` and `version` read
+    /// from the environment, following the GCP agent conventions:
+    ///
+    /// - `project_id`: `GOOGLE_CLOUD_PROJECT`, then `GCLOUD_PROJECT`
+    /// - `service`: `GAE_SERVICE`, then `K_SERVICE`
+    /// - `version`: `GAE_VERSION`, then `K_REVISION`
+    ///
+    /// Any value set explicitly on the builder afterwards takes precedence.
+    pub fn from_env() -> Self {
+        let env = envy::from_env::<EnvConfiguration>().unwrap_or_default();
+        ProfilerBuilder {
+            target: ProfilerTarget {
+                project_id: non_empty(env.google_cloud_project).or(non_empty(env.gcloud_project)),
+                service: non_empty(env.gae_service).or(non_empty(env.k_service)),
+                version: non_empty(env.gae_version).or(non_empty(env.k_revision)),
+                ..ProfilerTarget::default()
+            },
+            ..Self::default()
+        }
+    }
+
+    /// The GCP project profiles are uploaded to and attributed to. Sets both
+    /// the `profiles_create` parent (`projects/{project_id}`) and the
+    /// deployment's project, which `parent` and `deployment_project_id`
+    /// override independently.
+    pub fn project_id(mut self, project_id: impl Into<String>) -> Self {
+        self.target.project_id = Some(project_id.into());
+        self
+    }
+
+    /// Overrides the parent resource profiles are created under, for
+    /// non-standard GCP resource hierarchies. Defaults to
+    /// `projects/{project_id}`. Must start with `projects/`, `start` fails
+    /// with `ProfilerConfigError::InvalidParent` otherwise.
+    pub fn parent(mut self, parent: impl Into<String>) -> Self {
+        self.target.parent = Some(parent.into());
+        self
+    }
+
+    /// Sends all API calls to the regional endpoint of `region` (e.g.
+    /// `europe-west1`), `https://cloudprofiler.{region}.rep.googleapis.com/`,
+    /// for data residency requirements. The global endpoint is used by
+    /// default. `start` fails with `ProfilerConfigError::InvalidRegion` unless the
+    /// region is made of lower case letters, digits and hyphens.
+    pub fn region(mut self, region: impl Into<String>) -> Self {
+        self.target.region = Some(region.into());
+        self
+    }
+
+    /// Overrides the project recorded in the profiles' `Deployment`, the
+    /// logical project shown in the UI, when it differs from the project
+    /// profiles are uploaded to. Defaults to `project_id`.
+    pub fn deployment_project_id(mut self, project_id: impl Into<String>) -> Self {
+        self.target.deployment_project_id = Some(project_id.into());
+        self
+    }
+
+    pub fn service(mut self, service: impl Into<String>) -> Self {
+        self.target.service = Some(service.into());
+        self
+    }
+
+    pub fn version(mut self, version: impl Into<String>) -> Self {
+        self.target.version = Some(version.into());
+        self
+    }
+
+    /// Uses `cargo_version` as the version unless one is set with `version`,
+    /// which takes precedence whatever the order of the calls. Pass the
+    /// version of the binary's crate with `cargo_version!()`, which must be
+    /// expanded in that crate, or any build-time variable.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use cloud_profiler_rust::{cargo_version, ProfilerBuilder};
+    ///
+    /// # #[tokio::main(flavor = "current_thread")]
+    /// # async fn main() {
+    /// let handle = ProfilerBuilder::new()
+    ///     .project_id("my-gcp-project-id")
+    ///     .service("my-service")
+    ///     .version_from_cargo(cargo_version!())
+    ///     .start()
+    ///     .await;
+    /// assert!(handle.is_ok());
+    /// # }
+    /// ```
+    pub fn version_from_cargo(mut self, cargo_version: &str) -> Self {
+        if is_empty(&self.target.version) {
+            self.target.version = Some(cargo_version.to_string());
+        }
+        self
+    }
+
+    /// Polled before every profiling cycle, profiling is skipped while
+    /// this returns false.
+    pub fn should_start<F>(mut self, should_start: F) -> Self
+    where
+        F: Fn() -> bool + Send + Sync + 'static,
+    {
+        self.should_start = Arc::new(should_start);
+        self
+    }
+
+    /// Polled before every profiling cycle to fetch the configuration
+    /// used for that cycle.
+    pub fn get_configuration<G>(mut self, get_configuration: G) -> Self
+    where
+        G: Fn() -> CloudProfilerConfiguration + Send + Sync + 'static,
+    {
+        self.get_configuration = Arc::new(get_configuration);
+        self
+    }
+
+    /// Sleeps for a random duration between zero and `max_jitter` before
+    /// the first profiling cycle, so that replicas started together (e.g.
+    /// during a deploy) don't all create profiles at the same moment.
+    pub fn startup_jitter(mut self, max_jitter: Duration) -> Self {
+        self.startup_jitter = Some(max_jitter);
+        self
+    }
+
+    /// Sleeps for a random duration between `floor` and `ceiling` after
+    /// every successful cycle, so replicas whose cycles happen to line up
+    /// drift apart again and uploads are spread out. Defaults to between
+    /// zero and five seconds; pass zero for both to disable. A
+    /// `ProfilerHandle::profile_now` request cuts the pause short.
+    pub fn cycle_jitter(mut self, floor: Duration, ceiling: Duration) -> Self {
+        self.cycle_jitter = (floor.min(ceiling), floor.max(ceiling));
+        self
+    }
+
+    /// Starts every cycle at the next multiple of `interval` since the Unix
+    /// epoch on the wall clock (e.g. the top of each minute for one minute),
+    /// so cycles of different instances line up for comparison. The server
+    /// still decides when it hands out a profile, the collection window
+    /// starts then. The wait is computed from the wall clock and slept on
+    /// the monotonic clock, so a clock step only shifts the next boundary.
+    /// Applies after backoff and `cycle_jitter`, not to `profile_now`
+    /// requests. Cycles are not aligned by default.
+    pub fn align_cycles(mut self, interval: Duration) -> Self {
+        self.align_cycles = Some(interval).filter(|i| !i.is_zero());
+        self
+    }
+
+    /// Additional deployment labels. These take precedence over any
+    /// automatically detected label with the same key, unless
+    /// `label_precedence` says otherwise.
+    pub fn labels(mut self, labels: HashMap<String, String>) -> Self {
+        self.labels = labels;
+        self
+    }
+
+    /// Convenience for `labels` taking a slice of static pairs.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let builder = cloud_profiler_rust::ProfilerBuilder::new()
+    ///     .with_labels(&[("team", "infra"), ("region", "us-central1")]);
+    /// ```
+    pub fn with_labels(self, labels: &[(&str, &str)]) -> Self {
+        self.labels(
+            labels
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        )
+    }
+
+    /// Enables or disables automatically detected deployment labels, such
+    /// as the Kubernetes `pod` and `node`, the GCE `zone` and `instance`, the
+    /// compute `platform` or the `cloud_profiler_rust_version` this agent was
+    /// built from. Enabled by default.
+    pub fn auto_labels(mut self, enabled: bool) -> Self {
+        self.auto_labels = enabled;
+        self
+    }
+
+    /// Declares the platform the process runs on instead of detecting it,
+    /// reported as the `platform` label. A declared platform also skips the
+    /// check that the process runs on GCP (a metadata server probe), saving
+    /// its latency at startup. Platforms are detected by default.
+    pub fn platform(mut self, platform: Platform) -> Self {
+        self.platform = Some(platform);
+        self
+    }
+
+    /// Which label wins when a label passed to `labels` and an automatically
+    /// detected one (including `process_start_epoch`) share a key. Defaults
+    /// to `LabelPrecedence::UserWins`.
+    pub fn label_precedence(mut self, precedence: LabelPrecedence) -> Self {
+        self.label_precedence = precedence;
+        self
+    }
+
+    /// Re-detects the labels read from the GCE metadata server (`zone` and
+    /// `instance`) every `interval`, for long-running processes whose
+    /// instance may change, e.g. through live migration. Unset by default:
+    /// the labels detected at startup are kept for the process lifetime,
+    /// unless `ProfilerHandle::refresh_metadata` is called.
+    pub fn metadata_refresh(mut self, interval: Duration) -> Self {
+        self.metadata_refresh = Some(interval);
+        self
+    }
+
+    /// Adds a `process_start_epoch` deployment label, the process start time
+    /// in seconds since the Unix epoch, to tell profiles of a freshly
+    /// restarted instance apart from a long-running one. Disabled by default,
+    /// a `process_start_epoch` entry in `labels` takes precedence.
+    pub fn process_start_label(mut self, enabled: bool) -> Self {
+        self.process_start_label = enabled;
+        self
+    }
+
+    /// Maximum number of deployment labels, defaults to GCP's limit of 64.
+    /// Excess labels are dropped with a warning, automatically detected ones
+    /// first, unless `reject_excess_labels` is set.
+    pub fn max_labels(mut self, limit: usize) -> Self {
+        self.max_labels = limit;
+        self
+    }
+
+    /// Makes `start` fail with `ProfilerConfigError::TooManyLabels` instead of
+    /// dropping labels over the `max_labels` limit.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use cloud_profiler_rust::{ProfilerConfigError, ProfilerBuilder};
+    ///
+    /// # #[tokio::main(flavor = "current_thread")]
+    /// # async fn main() {
+    /// let labels = (0..10).map(|i| (format!("key{}", i), "value".to_string()));
+    /// let error = ProfilerBuilder::new()
+    ///     .project_id("my-gcp-project-id")
+    ///     .service("my-service")
+    ///     .version("v1")
+    ///     .auto_labels(false)
+    ///     .labels(labels.collect())
+    ///     .max_labels(8)
+    ///     .reject_excess_labels(true)
+    ///     .start()
+    ///     .await
+    ///     .err();
+    /// assert_eq!(
+    ///     error,
+    ///     Some(ProfilerConfigError::TooManyLabels { count: 12, limit: 8 })
+    /// );
+    /// # }
+    /// ```
+    pub fn reject_excess_labels(mut self, enabled: bool) -> Self {
+        self.reject_excess_labels = enabled;
+        self
+    }
+
+    /// Makes `start` fail with `ProfilerConfigError::UnsupportedProfileType` when
+    /// the configuration requests a profile type the Cloud Profiler API
+    /// doesn't know (see `ProfileType::is_supported`), instead of warning
+    /// about it. The configuration is checked once, when starting.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use cloud_profiler_rust::{
+    ///     ProfilerConfigError, CloudProfilerConfiguration, ProfileType, ProfilerBuilder,
+    /// };
+    ///
+    /// # #[tokio::main(flavor = "current_thread")]
+    /// # async fn main() {
+    /// let error = ProfilerBuilder::new()
+    ///     .project_id("my-gcp-project-id")
+    ///     .service("my-service")
+    ///     .version("v1")
+    ///     .get_configuration(|| {
+    ///         let mut configuration = CloudProfilerConfiguration::default();
+    ///         configuration.profile_types =
+    ///             vec![ProfileType::Wall, ProfileType::Other("CPUU".to_string())];
+    ///         configuration
+    ///     })
+    ///     .reject_unsupported_profile_types(true)
+    ///     .start()
+    ///     .await
+    ///     .err();
+    /// assert_eq!(
+    ///     error,
+    ///     Some(ProfilerConfigError::UnsupportedProfileType("CPUU".to_string()))
+    /// );
+    /// # }
+    /// ```
+    pub fn reject_unsupported_profile_types(mut self, enabled: bool) -> Self {
+        self.reject_unsupported_profile_types = enabled;
+        self
+    }
+
+    /// Creates profiles without a `Deployment`, so the pipeline can be
+    /// smoke-tested without a real service/version set up.
+    ///
+    /// This is intended for local experimentation only: profiles created
+    /// this way are not attributed to any service in the GCP UI, and the
+    /// API rejects deployment-less requests in most modes.
+    pub fn without_deployment(mut self) -> Self {
+        self.target.without_deployment = true;
+        self
+    }
+
+    /// Uses `deployment` as is for every profile instead of building it
+    /// from `deployment_project_id`/`project_id`, `service`, `version` and
+    /// the user and detected labels, which are then ignored. Only the
+    /// per-cycle labels of the configuration are still merged in. Its
+    /// `project_id` and `target` are required, and unless `parent` or
+    /// `project_id` is set profiles are created under its project. `start`
+    /// fails with `MissingProjectId` or `MissingService` otherwise.
+    pub fn deployment(mut self, deployment: crate::Deployment) -> Self {
+        self.deployment = Some(deployment);
+        self
+    }
+
+    /// Path to a service account key file used to authenticate against GCP.
+    ///
+    /// Credentials are resolved in order of precedence: this file, then
+    /// `GOOGLE_APPLICATION_CREDENTIALS`, then the GCE metadata server. Since
+    /// an explicit key file is typically used off GCP, setting it also skips
+    /// the check that the process is running on GCE.
+    pub fn credentials_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.credentials_file = Some(path.into());
+        self
+    }
+
+    /// Authenticates every API call with the token returned by `provider`,
+    /// called before each request, instead of resolving credentials, e.g.
+    /// for a sidecar injecting tokens or an air-gapped setup behind an
+    /// authenticating proxy. An error fails the call with
+    /// `FailedToGetAuthToken`. This takes precedence over
+    /// `credentials_file` and, like it, skips the GCE check.
+    pub fn token_provider<T>(mut self, provider: T) -> Self
+    where
+        T: Fn() -> Result<String, String> + Send + Sync + 'static,
+    {
+        self.token_provider = Some(Arc::new(provider));
+        self
+    }
+
+    /// Authenticates every API call with a fixed bearer token, see
+    /// `token_provider`. The token is never refreshed: the caller has to
+    /// restart the profiler before it expires, prefer `token_provider` for
+    /// tokens that need refreshing.
+    pub fn bearer_token(self, token: impl Into<String>) -> Self {
+        let token = token.into();
+        self.token_provider(move || Ok(token.clone()))
+    }
+
+    /// Logs a warning, once per window, whenever no profile has been
+    /// uploaded for `healthy_within` while profiling is enabled.
+    pub fn healthy_within(mut self, healthy_within: Duration) -> Self {
+        self.healthy_within = Some(healthy_within);
+        self
+    }
+
+    /// Maximum number of concurrent requests to the metadata server and
+    /// token endpoint, defaults to 1. This is a process-wide setting: only
+    /// the first profiler started sets it, and a later one asking for a
+    /// different limit logs a warning.
+    pub fn metadata_concurrency(mut self, limit: usize) -> Self {
+        self.metadata_concurrency = Some(limit);
+        self
+    }
+
+    /// Maximum number of concurrent profile uploads, defaults to 2. Uploads
+    /// over the limit wait for a slot rather than fail. Like
+    /// `metadata_concurrency`, the limit is process-wide, shared by all the
+    /// profilers, e.g. one per deployment.
+    pub fn upload_concurrency(mut self, limit: usize) -> Self {
+        self.upload_concurrency = Some(limit);
+        self
+    }
+
+    /// The target of the records the crate emits through `log`, to filter
+    /// or route them apart from the application's, defaults to
+    /// `cloud_profiler`. Every profiler in the process logs under the
+    /// target of the first one started, see `metadata_concurrency`.
+    pub fn log_target(mut self, target: impl Into<String>) -> Self {
+        self.log_target = Some(target.into());
+        self
+    }
+
+    /// Uploads heap profiles collected by the application's own tooling.
+    ///
+    /// When set, `ProfileType::Heap` is offered to the server on every
+    /// cycle, and whenever the server asks for a heap profile this closure
+    /// is called instead of running pprof. It must return an uncompressed,
+    /// serialized pprof `profile.proto` (see
+    /// <https://github.com/google/pprof/blob/main/proto/profile.proto>),
+    /// the crate takes care of compressing and uploading it. Use the same
+    /// sample types as the Go agent's heap profiles: `inuse_objects`/`count`
+    /// and `inuse_space`/`bytes`.
+    pub fn heap_profile<H>(mut self, heap_profile: H) -> Self
+    where
+        H: Fn() -> Result<Vec<u8>, String> + Send + Sync + 'static,
+    {
+        self.heap_profile = Some(Arc::new(heap_profile));
+        self
+    }
+
+    /// Stops the profiler once it has been running for `max_lifetime`, for
+    /// batch jobs and CI runs that shouldn't leave the background task
+    /// running forever. As with `ProfilerHandle::stop`, a cycle still in
+    /// flight at that point is abandoned without uploading.
+    /// `ProfilerHandle::join` resolves once the profiler has stopped.
+    pub fn max_lifetime(mut self, max_lifetime: Duration) -> Self {
+        self.max_lifetime = Some(max_lifetime);
+        self
+    }
+
+    /// Allows at most `max_retries` retries of failed cycles per `window`,
+    /// on top of the exponential backoff, so a flapping backend can't cause
+    /// sustained load. Once the budget is exhausted the profiler sleeps
+    /// until it has refilled enough for the next retry.
+    pub fn retry_budget(mut self, max_retries: u32, window: Duration) -> Self {
+        self.retry_budget = Some((max_retries, window));
+        self
+    }
+
+    /// How the retry backoff recovers once a cycle succeeds again: snapping
+    /// back to its minimum (the default) or decaying gradually towards it.
+    pub fn backoff_reset(mut self, strategy: BackoffReset) -> Self {
+        self.backoff_reset = strategy;
+        self
+    }
+
+    /// Slows down the whole cadence once `failures` cycles in a row have
+    /// failed, so a struggling backend isn't sent profile creations that
+    /// likely can't be uploaded. From then on cycles are at least the
+    /// minimum backoff envelope (a minute) apart, doubling with every
+    /// further failure up to the maximum envelope (an hour), on top of the
+    /// randomized backoff. The first successful cycle restores the
+    /// normal cadence. Defaults to 5, 0 disables it.
+    pub fn degrade_after(mut self, failures: u32) -> Self {
+        self.degrade_after = failures;
+        self
+    }
+
+    /// Talks to the GCP profiler server over HTTP/2 only, negotiated through
+    /// ALPN, for proxies that forbid HTTP/1. The GCP API endpoints support
+    /// h2, but a proxy that can't negotiate it will fail every request.
+    pub fn http2_only(mut self, enabled: bool) -> Self {
+        self.http2_only = enabled;
+        self
+    }
+
+    /// Maximum number of profiles created on the server whose upload failed
+    /// to keep around, defaults to 1. Instead of creating a new profile, the
+    /// next cycle retries uploading the oldest one, so reservations don't
+    /// accumulate on the server while uploads are failing. The oldest is
+    /// dropped once the limit is reached, 0 disables retrying uploads.
+    pub fn max_outstanding_profiles(mut self, limit: usize) -> Self {
+        self.max_outstanding = limit;
+        self
+    }
+
+    /// Number of cycle outcomes kept for `ProfilerHandle::recent_outcomes`,
+    /// defaults to 16. The oldest outcome is dropped once the limit is
+    /// reached, 0 disables keeping them.
+    pub fn recent_outcomes(mut self, limit: usize) -> Self {
+        self.recent_outcomes = limit;
+        self
+    }
+
+    /// Initial capacity in bytes of the buffer the first profile is
+    /// compressed into, defaults to 64 KiB. Later profiles start from the
+    /// largest compressed size so far, so only the first upload of a large
+    /// profile pays for growing the buffer.
+    pub fn gzip_capacity(mut self, bytes: usize) -> Self {
+        self.gzip_capacity = bytes;
+        self
+    }
+
+    /// Escape hatch for advanced use: called with every `CreateProfileRequest`
+    /// right before it is sent, e.g. to set fields this crate doesn't model
+    /// yet. It runs after the deployment and profile types have been filled
+    /// in from the configuration, so anything it changes there replaces the
+    /// configured values without being validated.
+    pub fn customize_create_request<C>(mut self, customize: C) -> Self
+    where
+        C: Fn(&mut crate::CreateProfileRequest) + Send + Sync + 'static,
+    {
+        self.customize_create_request = Some(Arc::new(customize));
+        self
+    }
+
+    /// Collects every profile for exactly `duration` instead of the duration
+    /// requested by the server, e.g. for reproducible benchmarks together
+    /// with `once`.
+    pub fn override_duration(mut self, duration: Duration) -> Self {
+        self.override_duration = Some(duration);
+        self
+    }
+
+    /// How long idle connections to the GCP endpoints are kept open for
+    /// reuse, defaults to hyper's default of 90 seconds.
+    pub fn pool_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.pool_idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Maximum number of idle connections kept open per host, unbounded by
+    /// default as in hyper.
+    pub fn pool_max_idle_per_host(mut self, max_idle: usize) -> Self {
+        self.pool_max_idle_per_host = Some(max_idle);
+        self
+    }
+
+    /// Rebuilds the HTTP client, dropping its pooled connections, once it
+    /// is older than `max_age`, so connections don't go stale when the
+    /// endpoint's load balancers rotate. The client is checked before each
+    /// request and kept for the profiler's whole lifetime by default.
+    pub fn client_max_age(mut self, max_age: Duration) -> Self {
+        self.client_max_age = Some(max_age);
+        self
+    }
+
+    /// Number of attempts, a second apart, at building the TLS connector
+    /// before the cycle fails with `FailedToBuildHttpClient`. Loading the
+    /// platform's root certificates can fail while a container's cert store
+    /// is still initializing. Defaults to 3, at least one attempt is made.
+    /// Independent of the retries of failed API calls.
+    pub fn connector_attempts(mut self, attempts: u32) -> Self {
+        self.connector_attempts = attempts.max(1);
+        self
+    }
+
+    /// Consulted before every profile collected by pprof is uploaded, with
+    /// its type, the profile created on the server and the bytes uploaded
+    /// for it: the gzip compressed, serialized pprof `profile.proto`, e.g.
+    /// decompressed with `flate2::read::GzDecoder` to inspect the samples.
+    /// Returning false skips the upload, e.g. to drop profiles dominated by
+    /// a known noisy function: the profile reserved on the server is
+    /// released with an empty upload, and the cycle ends with
+    /// `GcpCloudProfilingError::UploadSkipped` and a
+    /// `ProfilerEvent::ProfileSkipped`, without backing off. The type is the
+    /// one the server asked for, which allows capturing only one type's
+    /// output while debugging it. Everything is uploaded by default.
+    pub fn should_upload<U>(mut self, should_upload: U) -> Self
+    where
+        U: Fn(&crate::ProfileType, &crate::Profile, &[u8]) -> bool + Send + Sync + 'static,
+    {
+        self.should_upload = Some(Arc::new(should_upload));
+        self
+    }
+
+    /// Called right before every profile is uploaded for labels attached to
+    /// that profile alone, e.g. the request rate at collection time. The
+    /// server merges them with the deployment labels, so they are subject to
+    /// the same validation rules and limits, and they win on conflicting
+    /// keys. Only uploads are annotated: `profiles.create` takes nothing but
+    /// the deployment.
+    pub fn profile_annotations<A>(mut self, annotations: A) -> Self
+    where
+        A: Fn() -> HashMap<String, String> + Send + Sync + 'static,
+    {
+        self.profile_annotations = Some(Arc::new(annotations));
+        self
+    }
+
+    /// Called with structured events of the profiling loop, e.g. to chart
+    /// its backoff in a monitoring system. The hook runs on the profiling
+    /// task and should return quickly. No hook is set by default.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use cloud_profiler_rust::{ProfilerBuilder, ProfilerEvent};
+    ///
+    /// let builder = ProfilerBuilder::new().on_event(|event| match event {
+    ///     ProfilerEvent::BackingOff { seconds, reason } => {
+    ///         println!("profiler backing off for {}s after a {} error", seconds, reason)
+    ///     }
+    ///     ProfilerEvent::ProfileSent { sink, error: Some(error), .. } => {
+    ///         println!("profiler failed to send a profile to {}: {}", sink, error)
+    ///     }
+    ///     ProfilerEvent::ProfileCreated { name, requested_duration } => {
+    ///         println!("server requested {:?} for {}", requested_duration, name)
+    ///     }
+    ///     ProfilerEvent::ProfileSkipped { profile, reason } => {
+    ///         println!("profiler skipped {}: {}", profile, reason)
+    ///     }
+    ///     _ => {}
+    /// });
+    /// ```
+    pub fn on_event<E>(mut self, on_event: E) -> Self
+    where
+        E: Fn(&crate::ProfilerEvent) + Send + Sync + 'static,
+    {
+        self.on_event = Some(Arc::new(on_event));
+        self
+    }
+
+    /// Also keeps every uploaded profile in `dir` as `<profile id>.pb.gz`,
+    /// the gzip compressed pprof uploaded to GCP, readable with `pprof`.
+    /// Archived profiles older than `retention` are removed after each
+    /// write. The profile is archived before the upload, and either failing
+    /// doesn't prevent the other; failures are logged and reported as
+    /// `ProfilerEvent::ProfileSent`. Nothing is archived by default.
+    pub fn archive_dir(mut self, dir: impl Into<PathBuf>, retention: Duration) -> Self {
+        self.archive = Some((dir.into(), retention));
+        self
+    }
+
+    /// Also writes every collected CPU/wall report to `dir` as
+    /// `<profile id>.speedscope.json`, for local analysis with speedscope.
+    /// Failing to write a report is logged and doesn't affect the upload;
+    /// combine with `should_upload(|_, _, _| false)` to only export locally.
+    /// Heap profiles from `heap_profile` are not exported. Reports hold
+    /// aggregated stacks, so the exported samples are not in time order.
+    #[cfg(feature = "speedscope")]
+    pub fn speedscope_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.speedscope_dir = Some(dir.into());
+        self
+    }
+
+    /// Runs the profiling loop on a dedicated OS thread with its own
+    /// single-threaded tokio runtime instead of spawning it on the caller's
+    /// runtime, pinned to `cpu` when set (Linux only), to keep the
+    /// profiler's work off latency-critical cores. The threads collecting
+    /// profiles are spawned from it and inherit the affinity. A `cpu` beyond
+    /// what a `cpu_set_t` holds (1024 CPUs) is ignored with a warning.
+    /// `start` still has to be called from within a tokio runtime.
+    #[cfg(feature = "dedicated-thread")]
+    pub fn dedicated_thread(mut self, cpu: Option<usize>) -> Self {
+        self.dedicated_thread = true;
+        self.cpu_affinity = cpu;
+        self
+    }
+
+    /// Skips uploading a profile, logging it at debug level, when more than
+    /// `max_delay` has passed since its data was collected, e.g. because
+    /// the upload was slowed down by congestion or is a retry of an earlier
+    /// failure. Such a profile would be stale by the time the server gets
+    /// it, and the next cycle is likely due. The cycle ends with
+    /// `GcpCloudProfilingError::UploadSkipped` and a
+    /// `ProfilerEvent::ProfileSkipped`, without backing off. Profiles are
+    /// uploaded however late by default.
+    pub fn max_upload_delay(mut self, max_delay: Duration) -> Self {
+        self.max_upload_delay = Some(max_delay);
+        self
+    }
+
+    /// Kill switch for profiling, on by default. When off, `start` returns an
+    /// already finished handle without validating the configuration or
+    /// making any request, regardless of `should_start` and
+    /// `CLOUD_PROFILER_FORCE_ENABLE`. `should_start` is consulted every cycle
+    /// for dynamic gating; this is decided once, at startup.
+    pub fn enabled(mut self, enabled: bool) -> Self {
+        self.enabled = enabled;
+        self
+    }
+
+    /// Validates the configuration and starts profiling in a background
+    /// task. `project_id` is required unless it is overridden by `parent`
+    /// and `deployment_project_id` (or `without_deployment`), and `service`
+    /// and `version` are required unless `without_deployment` is used.
+    ///
+    /// Several profilers, e.g. one per deployment, can be started from
+    /// separate builders. Each runs its own loop with its own backoff,
+    /// retry budget and outstanding profiles, so one deployment failing
+    /// doesn't slow down the others. Only their collection windows are
+    /// serialized, since pprof's sampler is process-global.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use cloud_profiler_rust::{ProfilerConfigError, ProfilerBuilder};
+    ///
+    /// # #[tokio::main(flavor = "current_thread")]
+    /// # async fn main() {
+    /// let builder = || {
+    ///     ProfilerBuilder::new()
+    ///         .project_id("my-gcp-project-id")
+    ///         .service("my-service")
+    ///         .version("v1")
+    /// };
+    /// let error = builder().project_id("").start().await.err();
+    /// assert_eq!(error, Some(ProfilerConfigError::MissingProjectId));
+    /// let error = builder().service("").start().await.err();
+    /// assert_eq!(error, Some(ProfilerConfigError::MissingService));
+    /// let error = builder().version("").start().await.err();
+    /// assert_eq!(error, Some(ProfilerConfigError::MissingVersion));
+    ///
+    /// // The parent is checked with a complete deployment too
+    /// let deployment = cloud_profiler_rust::Deployment {
+    ///     project_id: Some("my-gcp-project-id".to_string()),
+    ///     target: Some("my-service".to_string()),
+    ///     ..Default::default()
+    /// };
+    /// let error = ProfilerBuilder::new()
+    ///     .deployment(deployment)
+    ///     .parent("folders/1")
+    ///     .start()
+    ///     .await
+    ///     .err();
+    /// assert_eq!(
+    ///     error,
+    ///     Some(ProfilerConfigError::InvalidParent("folders/1".to_string()))
+    /// );
+    /// # }
+    /// ```
+    pub async fn start(self) -> Result<ProfilerHandle, ProfilerConfigError> {
+        if !self.enabled {
+            return Ok(ProfilerHandle::finished());
+        }
+        let configuration = self.validate()?;
+        crate::start_profiling(self, configuration).await
+    }
+
+    /// Runs a single create, collect and upload cycle in the current task
+    /// and returns the name of the uploaded profile, e.g. to smoke-test the
+    /// setup against a real project. `should_start`, the startup jitter and
+    /// the GCE check are skipped, and errors are returned instead of retried.
+    /// Like `ProfilerHandle::profile_now`, the cycle is never sampled out.
+    pub async fn once(self) -> Result<String, crate::GcpCloudProfilingError> {
+        let configuration = self
+            .validate()
+            .map_err(crate::GcpCloudProfilingError::InvalidConfiguration)?;
+        crate::profile_once(self, configuration).await
+    }
+
+    /// Checks the target and the current configuration, returning the
+    /// latter for the start of the profiler.
+    fn validate(&self) -> Result<CloudProfilerConfiguration, ProfilerConfigError> {
+        self.validate_target()?;
+        let configuration = (self.get_configuration)();
+        configuration.validate()?;
+        for profile_type in &configuration.profile_types {
+            if profile_type.is_supported() {
+                continue;
+            }
+            if self.reject_unsupported_profile_types {
+                return Err(ProfilerConfigError::UnsupportedProfileType(
+                    profile_type.as_str().to_string(),
+                ));
+            }
+            log::warn!(
+                target: crate::log_target(),
+                "[gcp cloud profiler] Profile type {:?} is not supported by Cloud Profiler and will never be collected",
+                profile_type.as_str()
+            );
+        }
+        Ok(configuration)
+    }
+
+    fn validate_target(&self) -> Result<(), ProfilerConfigError> {
+        let Some(deployment) = &self.deployment else {
+            return self.target.validate();
+        };
+        self.target.endpoint()?;
+        self.target.validate_parent()?;
+        if is_empty(&deployment.project_id) {
+            return Err(ProfilerConfigError::MissingProjectId);
+        }
+        if is_empty(&deployment.target) {
+            return Err(ProfilerConfigError::MissingService);
+        }
+        Ok(())
+    }
+}
+
+fn non_empty(value: Option<String>) -> Option<String> {
+    value.filter(|v| !v.is_empty())
+}
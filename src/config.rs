@@ -0,0 +1,544 @@
+//! Configuration types and validation, kept free of the async runtime and
+//! HTTP client so they can be built and checked on their own. The runtime
+//! glue lives in the crate root.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::str::FromStr;
+use thiserror::Error;
+
+const DEFAULT_SAMPLING_RATE: i32 = 100;
+
+/// The profile types that can be requested from the GCP profiler server.
+///
+/// Samples are collected with pprof, which drives its sampler from
+/// `ITIMER_PROF` (`SIGPROF`): the timer only advances while the process is
+/// consuming CPU. pprof does not expose an `ITIMER_REAL` mode, so both
+/// types are collected with the same CPU-time weighted sampler and differ
+/// only in how the upload is labelled:
+///
+/// - `Cpu` maps one-to-one onto `ITIMER_PROF` and is the accurate label.
+/// - `Wall` is kept for compatibility with earlier releases, but time spent
+///   blocked or sleeping is not represented in its samples.
+///
+/// There is no off-CPU (blocking) profile type. Sampling blocked stacks
+/// requires walking the stacks of threads that are not running, from a
+/// timer that fires on wall time, or tracing the scheduler (e.g. eBPF with
+/// elevated privileges). pprof offers neither, and its signal handler can
+/// only unwind the interrupted thread's own stack. Lock waits and IO stay
+/// invisible, as do idle tokio workers parked in the reactor.
+///
+/// `Heap` profiles are not collected by this crate, they are uploaded from
+/// the bytes returned by the closure registered with
+/// `ProfilerBuilder::heap_profile`.
+///
+/// `Other` passes a raw profile type string through to the server as is, for
+/// types this crate does not model yet. It is collected with the same
+/// sampler, prefer the modelled variants whenever possible.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum ProfileType {
+    Wall,
+    Cpu,
+    Heap,
+    Other(String),
+}
+
+impl ProfileType {
+    /// The string sent to and returned by the server for this type. The API
+    /// takes the names of its `ProfileType` proto enum, which are upper case
+    /// (`google-cloudprofiler2` passes them through as plain strings), so
+    /// this is the single place mapping variants onto them. `Other` strings
+    /// are sent exactly as given.
+    ///
+    /// ```
+    /// use cloud_profiler_rust::ProfileType;
+    ///
+    /// assert_eq!(ProfileType::Wall.as_str(), "WALL");
+    /// assert_eq!(ProfileType::Cpu.as_str(), "CPU");
+    /// assert_eq!(ProfileType::Heap.as_str(), "HEAP");
+    /// assert_eq!(ProfileType::Other("THREADS".to_string()).as_str(), "THREADS");
+    /// ```
+    pub fn as_str(&self) -> &str {
+        match self {
+            ProfileType::Wall => "WALL",
+            ProfileType::Cpu => "CPU",
+            ProfileType::Heap => "HEAP",
+            ProfileType::Other(profile_type) => profile_type,
+        }
+    }
+
+    /// Whether the Cloud Profiler API knows this type. `Other` strings must
+    /// be one of the names of the API's `ProfileType` enum, anything else
+    /// (e.g. a typo or a lower case name) is never handed out by the server.
+    ///
+    /// ```
+    /// use cloud_profiler_rust::ProfileType;
+    ///
+    /// assert!(ProfileType::Cpu.is_supported());
+    /// assert!(ProfileType::Other("CONTENTION".to_string()).is_supported());
+    /// assert!(!ProfileType::Other("cpu".to_string()).is_supported());
+    /// assert!(!ProfileType::Other("OFF_CPU".to_string()).is_supported());
+    /// ```
+    pub fn is_supported(&self) -> bool {
+        API_PROFILE_TYPES.contains(&self.as_str())
+    }
+}
+
+/// Names of the `ProfileType` enum of the Cloud Profiler API, other than
+/// `PROFILE_TYPE_UNSPECIFIED`.
+const API_PROFILE_TYPES: [&str; 7] = [
+    "CPU",
+    "WALL",
+    "HEAP",
+    "THREADS",
+    "CONTENTION",
+    "PEAK_HEAP",
+    "HEAP_ALLOC",
+];
+
+/// What to do with a cycle that collected no samples, e.g. because the
+/// process was idle for the whole profile duration.
+///
+/// - `UploadEmpty` uploads the empty profile, releasing the profile the
+///   server reserved for this cycle. This is what the official agents do
+///   and is the default.
+/// - `Skip` saves the upload, ending the cycle with
+///   `GcpCloudProfilingError::UploadSkipped`, but leaves the reserved
+///   profile dangling until the server expires it.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnEmptyProfile {
+    Skip,
+    UploadEmpty,
+}
+
+/// Per-cycle profiling configuration.
+///
+/// Stacks deeper than `pprof::MAX_DEPTH` (128) frames are truncated to their
+/// innermost frames, which can cut off the outer context of deeply nested
+/// futures. The depth and the sample buffer are fixed at compile time in
+/// pprof 0.13, its builder only exposes the frequency and a thread
+/// blocklist, so neither can be configured here.
+///
+/// Fields may be added in minor releases, so the struct can't be built with
+/// a literal: start from `CloudProfilerConfiguration::default()` and set
+/// the fields needed.
+#[derive(Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct CloudProfilerConfiguration {
+    /// Samples per second taken by pprof, must be positive.
+    pub sampling_rate: i32,
+    /// Profile types offered to the server when creating a profile, the
+    /// server picks which one to collect on each cycle.
+    #[serde(default = "default_profile_types")]
+    pub profile_types: Vec<ProfileType>,
+    /// Probability, between 0 and 1, that a cycle actually collects a
+    /// profile. Skipped cycles still create and release a profile on the
+    /// server, so the server-driven cadence is unchanged, and end with
+    /// `GcpCloudProfilingError::UploadSkipped`.
+    #[serde(default = "default_cycle_sampling_probability")]
+    pub cycle_sampling_probability: f64,
+    #[serde(default = "default_on_empty_profile")]
+    pub on_empty_profile: OnEmptyProfile,
+    /// Number of back-to-back collection windows, each lasting the duration
+    /// requested by the server, merged into a single upload. More windows
+    /// give richer profiles at the cost of less frequent uploads. Samples
+    /// with the same stack and thread have their counts summed, whichever
+    /// window they were sampled in, and the merged profile covers the total
+    /// duration of all windows.
+    #[serde(default = "default_profile_windows")]
+    pub profile_windows: u32,
+    /// Also samples while waiting for the server to hand out the next
+    /// profile, merging those samples into the upload, so uploaded profiles
+    /// cover (nearly) all of the time between uploads instead of only the
+    /// requested windows. Combined with `profile_windows`, this profiles
+    /// continuously while uploading less often. Sampling then never stops,
+    /// and the samples of cycles that end up skipped, uploading a heap
+    /// profile or failing are discarded.
+    ///
+    /// pprof's sampler is process-global, so this takes it over for the
+    /// whole wait. While other profilers run in the process, cycles only
+    /// sample the requested windows (logging a warning), and a profiler
+    /// started meanwhile waits for the pending wait to end before its first
+    /// window.
+    #[serde(default)]
+    pub continuous: bool,
+    /// Deployment labels for this cycle, merged over the labels fixed when
+    /// the profiler started. Since the configuration is fetched every cycle,
+    /// this suits labels that change at runtime, e.g. a `rollout` label of
+    /// `canary`, `stable` or `baseline` to compare rollout phases in the UI.
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+    /// Logs a warning when the profiler's own CPU time in a cycle exceeds
+    /// this percentage of the collection window. The measurement is rough:
+    /// it covers the thread starting the sampler and building the report,
+    /// not the sampling signal handler running on the application threads.
+    #[serde(default)]
+    pub overhead_warning_percent: Option<f64>,
+    /// Only keeps samples from threads whose name starts with one of these
+    /// prefixes, e.g. `tokio-runtime-worker`, matched case-sensitively.
+    /// Unnamed threads are matched on their id. pprof can only block
+    /// threads, not allow them, so the others are still sampled and then
+    /// dropped from the report: this focuses the profile without reducing
+    /// the sampling overhead. Empty, the default, keeps all threads.
+    #[serde(default)]
+    pub thread_allowlist: Vec<String>,
+}
+
+impl CloudProfilerConfiguration {
+    /// Checks the values pprof would otherwise reject with an unhelpful
+    /// error. Checked again before every collection.
+    ///
+    /// ```
+    /// use cloud_profiler_rust::{CloudProfilerConfiguration, ProfilerConfigError};
+    ///
+    /// let mut configuration = CloudProfilerConfiguration::default();
+    /// configuration.sampling_rate = 0;
+    /// assert_eq!(
+    ///     configuration.validate(),
+    ///     Err(ProfilerConfigError::InvalidSamplingRate(0))
+    /// );
+    /// ```
+    pub fn validate(&self) -> Result<(), ProfilerConfigError> {
+        if self.sampling_rate <= 0 {
+            return Err(ProfilerConfigError::InvalidSamplingRate(self.sampling_rate));
+        }
+        Ok(())
+    }
+}
+
+impl Default for CloudProfilerConfiguration {
+    fn default() -> Self {
+        CloudProfilerConfiguration {
+            sampling_rate: DEFAULT_SAMPLING_RATE,
+            profile_types: default_profile_types(),
+            cycle_sampling_probability: default_cycle_sampling_probability(),
+            on_empty_profile: default_on_empty_profile(),
+            profile_windows: default_profile_windows(),
+            continuous: false,
+            labels: HashMap::new(),
+            overhead_warning_percent: None,
+            thread_allowlist: Vec::new(),
+        }
+    }
+}
+
+fn default_profile_types() -> Vec<ProfileType> {
+    vec![ProfileType::Wall]
+}
+
+fn default_cycle_sampling_probability() -> f64 {
+    1.0
+}
+
+fn default_on_empty_profile() -> OnEmptyProfile {
+    OnEmptyProfile::UploadEmpty
+}
+
+fn default_profile_windows() -> u32 {
+    1
+}
+
+/// Which deployment label wins when a user supplied label and an
+/// automatically detected one (e.g. `zone`, `instance` or `platform`) have
+/// the same key. `language` and `version` are always set by the crate.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LabelPrecedence {
+    /// Explicitly configured labels are never clobbered. The default.
+    #[default]
+    UserWins,
+    /// Detected labels override configured ones, which then only act as
+    /// fallbacks when detection fails.
+    AutoWins,
+}
+
+impl LabelPrecedence {
+    /// Merges `user` and `auto` labels, resolving conflicting keys.
+    ///
+    /// ```
+    /// use cloud_profiler_rust::LabelPrecedence;
+    /// use std::collections::HashMap;
+    ///
+    /// let auto = HashMap::from([
+    ///     ("zone".to_string(), "us-central1-a".to_string()),
+    ///     ("platform".to_string(), "gce".to_string()),
+    /// ]);
+    /// let user = HashMap::from([
+    ///     ("zone".to_string(), "override".to_string()),
+    ///     ("team".to_string(), "infra".to_string()),
+    /// ]);
+    ///
+    /// let merged = LabelPrecedence::UserWins.merge(auto.clone(), &user);
+    /// assert_eq!(merged["zone"], "override");
+    /// assert_eq!(merged["platform"], "gce");
+    /// assert_eq!(merged["team"], "infra");
+    ///
+    /// let merged = LabelPrecedence::AutoWins.merge(auto, &user);
+    /// assert_eq!(merged["zone"], "us-central1-a");
+    /// assert_eq!(merged["platform"], "gce");
+    /// assert_eq!(merged["team"], "infra");
+    /// ```
+    pub fn merge(
+        self,
+        mut auto: HashMap<String, String>,
+        user: &HashMap<String, String>,
+    ) -> HashMap<String, String> {
+        for (key, value) in user {
+            match self {
+                LabelPrecedence::UserWins => {
+                    auto.insert(key.clone(), value.clone());
+                }
+                LabelPrecedence::AutoWins => {
+                    auto.entry(key.clone()).or_insert_with(|| value.clone());
+                }
+            }
+        }
+        auto
+    }
+}
+
+/// A GCP compute platform the process can be declared to run on with
+/// `ProfilerBuilder::platform`, reported as the `platform` label.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Platform {
+    Gce,
+    Gke,
+    CloudRun,
+    AppEngine,
+}
+
+impl Platform {
+    /// The value of the `platform` label for this platform.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Platform::Gce => "gce",
+            Platform::Gke => "gke",
+            Platform::CloudRun => "cloud_run",
+            Platform::AppEngine => "app_engine",
+        }
+    }
+}
+
+/// Parses a `platform` label value, e.g. from a configuration file.
+///
+/// ```
+/// use cloud_profiler_rust::{ProfilerConfigError, Platform};
+///
+/// assert_eq!("cloud_run".parse(), Ok(Platform::CloudRun));
+/// assert_eq!(
+///     "cloudrun".parse::<Platform>(),
+///     Err(ProfilerConfigError::UnknownPlatform("cloudrun".to_string()))
+/// );
+/// ```
+impl FromStr for Platform {
+    type Err = ProfilerConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [
+            Platform::Gce,
+            Platform::Gke,
+            Platform::CloudRun,
+            Platform::AppEngine,
+        ]
+        .into_iter()
+        .find(|platform| platform.as_str() == s)
+        .ok_or_else(|| ProfilerConfigError::UnknownPlatform(s.to_string()))
+    }
+}
+
+/// Why the profiler's configuration was rejected, returned by
+/// `ProfilerBuilder::start`: a required field is missing or empty, or a
+/// value is invalid. Every configuration check reports one of these.
+///
+/// ```
+/// use cloud_profiler_rust::{CloudProfilerConfiguration, ProfilerBuilder, ProfilerConfigError};
+///
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// let builder = || {
+///     ProfilerBuilder::new()
+///         .project_id("my-gcp-project-id")
+///         .service("my-service")
+///         .version("v1")
+/// };
+/// let error = builder().service("My Service").start().await.err();
+/// assert_eq!(
+///     error,
+///     Some(ProfilerConfigError::InvalidService("My Service".to_string()))
+/// );
+/// let error = builder().region("europe west1").start().await.err();
+/// assert_eq!(
+///     error,
+///     Some(ProfilerConfigError::InvalidRegion("europe west1".to_string()))
+/// );
+/// let error = builder()
+///     .get_configuration(|| {
+///         let mut configuration = CloudProfilerConfiguration::default();
+///         configuration.sampling_rate = -1;
+///         configuration
+///     })
+///     .start()
+///     .await
+///     .err();
+/// assert_eq!(error, Some(ProfilerConfigError::InvalidSamplingRate(-1)));
+/// # }
+/// ```
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ProfilerConfigError {
+    #[error("project_id must be set")]
+    MissingProjectId,
+    #[error("service must be set")]
+    MissingService,
+    #[error("version must be set")]
+    MissingVersion,
+    #[error("{count} deployment labels exceed the limit of {limit}")]
+    TooManyLabels { count: usize, limit: usize },
+    #[error("invalid region {0:?}, expected e.g. us-central1")]
+    InvalidRegion(String),
+    #[error(
+        "invalid service {0:?}, expected lower case letters, digits, '-', '_' and '.', \
+         starting with a letter"
+    )]
+    InvalidService(String),
+    #[error("invalid sampling rate {0}, must be a positive number of samples per second")]
+    InvalidSamplingRate(i32),
+    #[error("profile type {0:?} is not supported by Cloud Profiler")]
+    UnsupportedProfileType(String),
+    #[error("unknown platform {0:?}, expected one of gce, gke, cloud_run or app_engine")]
+    UnknownPlatform(String),
+    #[error("invalid parent {0:?}, expected projects/<project id>...")]
+    InvalidParent(String),
+}
+
+/// The global Cloud Profiler endpoint, used unless a region is set.
+pub(crate) const GLOBAL_ENDPOINT: &str = "https://cloudprofiler.googleapis.com/";
+
+/// GCP rejects resources with more labels than this.
+pub(crate) const DEFAULT_MAX_LABELS: usize = 64;
+
+/// Where profiles are created and what they are attributed to, as set on a
+/// `ProfilerBuilder`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ProfilerTarget {
+    pub project_id: Option<String>,
+    pub parent: Option<String>,
+    pub deployment_project_id: Option<String>,
+    pub service: Option<String>,
+    pub version: Option<String>,
+    pub without_deployment: bool,
+    /// Region of the regional endpoint to use instead of the global one.
+    pub region: Option<String>,
+}
+
+impl ProfilerTarget {
+    /// Checks that the region, if any, is valid and that the required fields
+    /// are set: `project_id` unless it is overridden by `parent` and
+    /// `deployment_project_id` (or `without_deployment`), and `service` and
+    /// `version` unless `without_deployment` is set. `service` must match
+    /// the server's `^[a-z]([-a-z0-9_.]{0,253}[a-z0-9])?$`, and `parent`
+    /// must name a resource under `projects/`.
+    ///
+    /// ```
+    /// use cloud_profiler_rust::{ProfilerConfigError, ProfilerTarget};
+    ///
+    /// let mut target = ProfilerTarget {
+    ///     project_id: Some("my-gcp-project-id".to_string()),
+    ///     service: Some("my-service".to_string()),
+    ///     ..Default::default()
+    /// };
+    /// assert_eq!(target.validate(), Err(ProfilerConfigError::MissingVersion));
+    /// target.version = Some("v1".to_string());
+    /// target.parent = Some("folders/123".to_string());
+    /// assert_eq!(
+    ///     target.validate(),
+    ///     Err(ProfilerConfigError::InvalidParent("folders/123".to_string()))
+    /// );
+    /// ```
+    pub fn validate(&self) -> Result<(), ProfilerConfigError> {
+        self.endpoint()?;
+        self.validate_parent()?;
+        let has_project_id = !is_empty(&self.project_id);
+        if self.parent.is_none() && !has_project_id {
+            return Err(ProfilerConfigError::MissingProjectId);
+        }
+        if self.without_deployment {
+            return Ok(());
+        }
+        if is_empty(&self.deployment_project_id) && !has_project_id {
+            return Err(ProfilerConfigError::MissingProjectId);
+        }
+        let service = match self.service.as_deref().map(str::trim) {
+            Some(service) if !service.is_empty() => service,
+            _ => return Err(ProfilerConfigError::MissingService),
+        };
+        if !is_valid_service(service) {
+            return Err(ProfilerConfigError::InvalidService(service.to_string()));
+        }
+        if is_empty(&self.version) {
+            return Err(ProfilerConfigError::MissingVersion);
+        }
+        Ok(())
+    }
+
+    /// Checks that `parent`, if set, names a resource under `projects/`.
+    pub(crate) fn validate_parent(&self) -> Result<(), ProfilerConfigError> {
+        if let Some(parent) = &self.parent {
+            let prefix = crate::profile_parent("");
+            if !parent.starts_with(&prefix) || parent.len() == prefix.len() {
+                return Err(ProfilerConfigError::InvalidParent(parent.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    /// The endpoint the API is called on: the regional endpoint
+    /// `https://cloudprofiler.{region}.rep.googleapis.com/` when a region
+    /// is set, the global endpoint otherwise.
+    ///
+    /// ```
+    /// use cloud_profiler_rust::{ProfilerConfigError, ProfilerTarget};
+    ///
+    /// let mut target = ProfilerTarget::default();
+    /// assert_eq!(target.endpoint(), Ok("https://cloudprofiler.googleapis.com/".to_string()));
+    /// target.region = Some("europe-west1".to_string());
+    /// assert_eq!(
+    ///     target.endpoint(),
+    ///     Ok("https://cloudprofiler.europe-west1.rep.googleapis.com/".to_string())
+    /// );
+    /// target.region = Some("europe-west1.evil.com/".to_string());
+    /// assert!(matches!(target.endpoint(), Err(ProfilerConfigError::InvalidRegion(_))));
+    /// ```
+    pub fn endpoint(&self) -> Result<String, ProfilerConfigError> {
+        let Some(region) = &self.region else {
+            return Ok(GLOBAL_ENDPOINT.to_string());
+        };
+        // Region names are lower case words and a zone number separated by
+        // hyphens, anything else would end up in the host name
+        let valid = region.starts_with(|c: char| c.is_ascii_lowercase())
+            && !region.ends_with('-')
+            && region
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+        if !valid {
+            return Err(ProfilerConfigError::InvalidRegion(region.clone()));
+        }
+        Ok(format!(
+            "https://cloudprofiler.{}.rep.googleapis.com/",
+            region
+        ))
+    }
+}
+
+fn is_valid_service(service: &str) -> bool {
+    service.len() <= 255
+        && service.starts_with(|c: char| c.is_ascii_lowercase())
+        && service.ends_with(|c: char| c.is_ascii_lowercase() || c.is_ascii_digit())
+        && service
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "-_.".contains(c))
+}
+
+pub(crate) fn is_empty(value: &Option<String>) -> bool {
+    value.as_deref().map_or(true, |v| v.trim().is_empty())
+}
@@ -0,0 +1,87 @@
+//! Runs the profiling loop on its own OS thread, optionally pinned to a CPU,
+//! enabled with the `dedicated-thread` feature.
+
+use std::future::Future;
+
+/// Spawns a thread running `task` to completion on a single-threaded tokio
+/// runtime. When `cpu` is set the thread is pinned to that CPU before the
+/// runtime starts, which its blocking pool threads (collecting the profile)
+/// inherit. Returns false when the thread or runtime could not be created.
+pub(crate) fn spawn<F>(task: F, cpu: Option<usize>) -> bool
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let (started, ready) = std::sync::mpsc::channel();
+    let spawned = std::thread::Builder::new()
+        .name("cloud-profiler".to_string())
+        .spawn(move || {
+            if let Some(cpu) = cpu {
+                pin_to_cpu(cpu);
+            }
+            let runtime = match tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+            {
+                Ok(runtime) => runtime,
+                Err(e) => {
+                    log::error!(
+                        target: crate::log_target(),
+                        "[gcp cloud profiler] Failed to build the profiler runtime: {}",
+                        e
+                    );
+                    let _ = started.send(false);
+                    return;
+                }
+            };
+            let _ = started.send(true);
+            runtime.block_on(task);
+        });
+    match spawned {
+        Ok(_) => ready.recv().unwrap_or(false),
+        Err(e) => {
+            log::error!(
+                target: crate::log_target(),
+                "[gcp cloud profiler] Failed to spawn the profiler thread: {}",
+                e
+            );
+            false
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn pin_to_cpu(cpu: usize) {
+    if cpu >= libc::CPU_SETSIZE as usize {
+        log::warn!(
+            target: crate::log_target(),
+            "[gcp cloud profiler] CPU {} is beyond the {} CPUs of a cpu_set_t, not pinning the profiler thread",
+            cpu,
+            libc::CPU_SETSIZE
+        );
+        return;
+    }
+    // SAFETY: `set` is a valid, zero-initialized cpu_set_t, and `cpu` was
+    // checked to be within its capacity above
+    let result = unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(cpu, &mut set);
+        libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set)
+    };
+    if result != 0 {
+        log::error!(
+            target: crate::log_target(),
+            "[gcp cloud profiler] Failed to pin the profiler thread to CPU {}: {}",
+            cpu,
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pin_to_cpu(cpu: usize) {
+    log::warn!(
+        target: crate::log_target(),
+        "[gcp cloud profiler] CPU affinity is only supported on Linux, not pinning to CPU {}",
+        cpu
+    );
+}
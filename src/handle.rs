@@ -0,0 +1,397 @@
+use crate::{GcpCloudProfilingError, ProfileType};
+use google_cloudprofiler2::api::Deployment;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{mpsc, oneshot, watch};
+
+/// How often the background task checks whether `stop` was called.
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Maximum number of `profile_now` requests waiting for a cycle.
+pub(crate) const PROFILE_NOW_QUEUE: usize = 8;
+
+/// A `profile_now` request, answered with the result of the cycle run for it.
+pub(crate) type ProfileRequest = oneshot::Sender<Result<String, GcpCloudProfilingError>>;
+
+/// Whether the profiler's background task is running, see
+/// `ProfilerHandle::status`.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProfilerState {
+    Running,
+    Paused,
+    Stopped,
+}
+
+/// Snapshot of the profiler's effective configuration and state, returned by
+/// `ProfilerHandle::status`. Serializable, e.g. for a config-dump endpoint.
+///
+/// The project, parent, target and labels are `None`/empty when profiling
+/// never started, and the project, target and labels when profiling without
+/// a deployment.
+#[derive(Serialize, Clone, Debug)]
+pub struct ProfilerStatus {
+    /// The deployment's project.
+    pub project_id: Option<String>,
+    /// The resource profiles are created under.
+    pub parent: Option<String>,
+    /// The deployment's target, i.e. the service.
+    pub target: Option<String>,
+    pub labels: HashMap<String, String>,
+    /// See `ProfilerHandle::active_profile_types`.
+    pub profile_types: Vec<ProfileType>,
+    pub state: ProfilerState,
+    /// See `ProfilerHandle::last_error`.
+    pub last_error: Option<String>,
+}
+
+/// The result of one profiling cycle, see `ProfilerHandle::recent_outcomes`.
+#[derive(Serialize, Clone, Debug)]
+pub struct CycleOutcome {
+    /// Sequential id of the cycle, as in the profiler's log lines.
+    pub cycle: u64,
+    /// The profile type the server handed out, `None` when the cycle failed
+    /// before a profile was created.
+    pub profile_type: Option<String>,
+    /// The error the cycle failed with, `None` if it succeeded.
+    pub error: Option<String>,
+    /// How long the cycle took, including waiting for the server.
+    pub duration: Duration,
+    /// Size of the uploaded profile before compression, 0 when nothing was
+    /// uploaded.
+    pub bytes: usize,
+    /// When the cycle ended.
+    pub finished_at: SystemTime,
+}
+
+/// A notable step of the profiling loop, passed to the hook registered with
+/// `ProfilerBuilder::on_event`.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum ProfilerEvent {
+    /// A cycle failed and the next one is delayed by `seconds`. `reason` is
+    /// the category of the error, see `GcpCloudProfilingError::category`.
+    BackingOff { seconds: f64, reason: String },
+    /// The server handed out the profile `name` and asked for it to be
+    /// collected for `requested_duration`, `None` when the server sent no
+    /// or a negative duration. `ProfilerBuilder::override_duration` may
+    /// collect for a different duration.
+    ProfileCreated {
+        name: String,
+        requested_duration: Option<Duration>,
+    },
+    /// The profile `profile` was handed to one of its destinations: `gcp`
+    /// for the upload, or `archive` for `ProfilerBuilder::archive_dir`.
+    /// `error` is `None` on success. A destination failing doesn't prevent
+    /// the others from receiving the profile.
+    ProfileSent {
+        sink: String,
+        profile: String,
+        error: Option<String>,
+    },
+    /// The profile `profile` was collected but not uploaded, see
+    /// `GcpCloudProfilingError::UploadSkipped`.
+    ProfileSkipped { profile: String, reason: String },
+}
+
+struct ProfilerControl {
+    stopped: AtomicBool,
+    paused: AtomicBool,
+    refresh_metadata: AtomicBool,
+    finished: watch::Sender<bool>,
+    profile_requests: OnceLock<mpsc::Sender<ProfileRequest>>,
+    last_error: RwLock<Option<GcpCloudProfilingError>>,
+    last_healthy: RwLock<Option<Instant>>,
+    active_profile_types: RwLock<Vec<ProfileType>>,
+    deployment: RwLock<Option<(Option<Deployment>, String)>>,
+    recent_outcomes: RwLock<VecDeque<CycleOutcome>>,
+}
+
+impl Default for ProfilerControl {
+    fn default() -> Self {
+        ProfilerControl {
+            stopped: AtomicBool::new(false),
+            paused: AtomicBool::new(false),
+            refresh_metadata: AtomicBool::new(false),
+            finished: watch::Sender::new(false),
+            profile_requests: OnceLock::new(),
+            last_error: RwLock::new(None),
+            last_healthy: RwLock::new(None),
+            active_profile_types: RwLock::new(Vec::new()),
+            deployment: RwLock::new(None),
+            recent_outcomes: RwLock::new(VecDeque::new()),
+        }
+    }
+}
+
+/// Handle to a running profiler, returned when profiling is started.
+///
+/// Dropping the handle does not stop the profiler. The handle is cheap to
+/// clone and is `Send + Sync`, so it can be moved into e.g. a shutdown or
+/// `tokio::signal` task.
+#[derive(Clone, Default)]
+pub struct ProfilerHandle {
+    control: Arc<ProfilerControl>,
+}
+
+impl ProfilerHandle {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// A handle for a profiler that never started.
+    pub(crate) fn finished() -> Self {
+        let handle = Self::new();
+        handle.mark_finished();
+        handle
+    }
+
+    /// Stops the profiler. Any in-flight cycle is abandoned and the
+    /// background task exits within 100ms.
+    ///
+    /// `stop` is idempotent and may be called any number of times from any
+    /// thread or task, e.g. from a task handling SIGTERM via
+    /// `tokio::signal`. Only the first call has an effect, it returns `true`
+    /// while every later call is a no-op returning `false`. It never blocks,
+    /// allocates, takes a lock or panics: it only swaps an atomic flag, which
+    /// the background task polls. It is therefore async-signal-safe and may
+    /// also be called from a raw `sigaction` handler, given a handle the
+    /// handler can reach (e.g. one stored in a `static`).
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # #[tokio::main(flavor = "current_thread")]
+    /// # async fn main() {
+    /// let handle = cloud_profiler_rust::ProfilerBuilder::new()
+    ///     .project_id("my-gcp-project-id")
+    ///     .service("my-service")
+    ///     .version("v1")
+    ///     .start()
+    ///     .await
+    ///     .unwrap();
+    /// assert!(handle.stop());
+    /// assert!(!handle.stop());
+    /// # }
+    /// ```
+    pub fn stop(&self) -> bool {
+        !self.control.stopped.swap(true, Ordering::SeqCst)
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.control.stopped.load(Ordering::SeqCst)
+    }
+
+    /// Pauses profiling without stopping the background task, e.g. during a
+    /// known-noisy batch job. Checked before every cycle independently of
+    /// `should_start`: while paused, the profiler sleeps just like when
+    /// `should_start` returns false. A cycle already in flight completes.
+    pub fn pause(&self) {
+        self.control.paused.store(true, Ordering::SeqCst);
+    }
+
+    /// Resumes profiling after `pause`, from the next cycle on.
+    pub fn resume(&self) {
+        self.control.paused.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.control.paused.load(Ordering::SeqCst)
+    }
+
+    /// The error that made the most recent profiling cycle fail, or `None`
+    /// if the most recent cycle uploaded successfully (or none has failed
+    /// yet). Useful for rendering the profiler's health in a status endpoint.
+    pub fn last_error(&self) -> Option<GcpCloudProfilingError> {
+        read(&self.control.last_error)
+    }
+
+    pub(crate) fn set_last_error(&self, error: GcpCloudProfilingError) {
+        write(&self.control.last_error, Some(error));
+    }
+
+    /// Records a successful upload, clearing the last error.
+    pub(crate) fn record_success(&self) {
+        write(&self.control.last_error, None);
+        self.mark_healthy();
+    }
+
+    /// Records that the profiler is in a healthy state, either because a
+    /// profile was uploaded or because profiling is currently disabled.
+    pub(crate) fn mark_healthy(&self) {
+        write(&self.control.last_healthy, Some(Instant::now()));
+    }
+
+    pub(crate) fn last_healthy(&self) -> Option<Instant> {
+        read(&self.control.last_healthy)
+    }
+
+    /// The profile types offered to the server by the most recent cycle,
+    /// reflecting any change made through `get_configuration`. Empty until
+    /// the first cycle has started.
+    pub fn active_profile_types(&self) -> Vec<ProfileType> {
+        read(&self.control.active_profile_types)
+    }
+
+    pub(crate) fn set_active_profile_types(&self, profile_types: Vec<ProfileType>) {
+        write(&self.control.active_profile_types, profile_types);
+    }
+
+    /// The profiler's effective configuration and current state.
+    pub fn status(&self) -> ProfilerStatus {
+        let (deployment, parent) = match read(&self.control.deployment) {
+            Some((deployment, parent)) => (deployment, Some(parent)),
+            None => (None, None),
+        };
+        let deployment = deployment.unwrap_or_default();
+        let state = if self.is_stopped() || *self.control.finished.borrow() {
+            ProfilerState::Stopped
+        } else if self.is_paused() {
+            ProfilerState::Paused
+        } else {
+            ProfilerState::Running
+        };
+        ProfilerStatus {
+            project_id: deployment.project_id,
+            parent,
+            target: deployment.target,
+            labels: deployment.labels.unwrap_or_default(),
+            profile_types: self.active_profile_types(),
+            state,
+            last_error: self.last_error().map(|e| e.to_string()),
+        }
+    }
+
+    pub(crate) fn set_deployment(&self, deployment: Option<Deployment>, parent: String) {
+        write(&self.control.deployment, Some((deployment, parent)));
+    }
+
+    /// The outcomes of the most recent profiling cycles, oldest first, for a
+    /// quick in-process view of the profiler's history. At most
+    /// `ProfilerBuilder::recent_outcomes` cycles are kept.
+    pub fn recent_outcomes(&self) -> Vec<CycleOutcome> {
+        read(&self.control.recent_outcomes).into()
+    }
+
+    /// Records the outcome of a cycle, dropping the oldest outcomes beyond
+    /// `limit`.
+    pub(crate) fn record_outcome(&self, outcome: CycleOutcome, limit: usize) {
+        let mut outcomes = match self.control.recent_outcomes.write() {
+            Ok(outcomes) => outcomes,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        outcomes.push_back(outcome);
+        while outcomes.len() > limit {
+            outcomes.pop_front();
+        }
+    }
+
+    /// Asks the profiler to re-detect the labels read from the metadata
+    /// server (`zone`, `instance`) before its next profiling cycle, e.g.
+    /// after a live migration. Has no effect when `auto_labels` is disabled
+    /// or a complete `Deployment` was given to the builder.
+    pub fn refresh_metadata(&self) {
+        self.control.refresh_metadata.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether `refresh_metadata` was called since the last check.
+    pub(crate) fn take_metadata_refresh(&self) -> bool {
+        self.control.refresh_metadata.swap(false, Ordering::SeqCst)
+    }
+
+    /// Requests a profiling cycle as soon as possible, independently of the
+    /// normal cadence, and resolves with the name of the uploaded profile.
+    /// The request interrupts any backoff or disabled sleep, and is served
+    /// even while profiling is paused or `should_start` returns false. The
+    /// cycle still waits for the server to hand out a profile, and is never
+    /// sampled out by `cycle_sampling_probability`. Profiles whose upload
+    /// failed earlier are completed first, they don't answer the request.
+    ///
+    /// At most a few requests can be pending at once, further requests fail
+    /// with `TooManyProfileRequests` until the next cycle has run.
+    pub async fn profile_now(&self) -> Result<String, GcpCloudProfilingError> {
+        let requests = self
+            .control
+            .profile_requests
+            .get()
+            .filter(|_| !self.is_stopped())
+            .ok_or(GcpCloudProfilingError::ProfilerNotRunning)?;
+        let (respond, response) = oneshot::channel();
+        requests.try_send(respond).map_err(|e| match e {
+            mpsc::error::TrySendError::Full(_) => GcpCloudProfilingError::TooManyProfileRequests,
+            mpsc::error::TrySendError::Closed(_) => GcpCloudProfilingError::ProfilerNotRunning,
+        })?;
+        response
+            .await
+            .unwrap_or(Err(GcpCloudProfilingError::ProfilerNotRunning))
+    }
+
+    /// Creates the channel `profile_now` requests are sent on.
+    pub(crate) fn profile_requests(&self) -> mpsc::Receiver<ProfileRequest> {
+        let (requests, receiver) = mpsc::channel(PROFILE_NOW_QUEUE);
+        let _ = self.control.profile_requests.set(requests);
+        receiver
+    }
+
+    /// Resolves once the background task has exited, either because `stop`
+    /// was called or because the configured `max_lifetime` elapsed. Resolves
+    /// immediately if profiling never started (e.g. not running on GCE).
+    pub async fn join(&self) {
+        let mut finished = self.control.finished.subscribe();
+        let _ = finished.wait_for(|finished| *finished).await;
+    }
+
+    pub(crate) fn mark_finished(&self) {
+        self.control.finished.send_replace(true);
+    }
+
+    /// Resolves once `stop` has been called. Polled rather than notified,
+    /// so that `stop` stays a single atomic operation.
+    pub(crate) async fn stopped(&self) {
+        while !self.is_stopped() {
+            tokio::time::sleep(STOP_POLL_INTERVAL).await;
+        }
+    }
+}
+
+fn read<T: Clone>(lock: &RwLock<T>) -> T {
+    match lock.read() {
+        Ok(value) => value.clone(),
+        Err(poisoned) => poisoned.into_inner().clone(),
+    }
+}
+
+fn write<T>(lock: &RwLock<T>, value: T) {
+    match lock.write() {
+        Ok(mut current) => *current = value,
+        Err(poisoned) => *poisoned.into_inner() = value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn second_stop_is_a_no_op() {
+        let handle = ProfilerHandle::new();
+        assert!(handle.stop());
+        assert!(!handle.stop());
+        assert!(handle.is_stopped());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn background_task_sees_the_stop() {
+        let handle = ProfilerHandle::new();
+        let stopper = handle.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            stopper.stop();
+        });
+        let started = tokio::time::Instant::now();
+        handle.stopped().await;
+        assert!(started.elapsed() <= Duration::from_secs(1) + STOP_POLL_INTERVAL);
+    }
+}
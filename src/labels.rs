@@ -0,0 +1,282 @@
+use crate::Platform;
+use google_cloudprofiler2::hyper;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Labels describing the build of this agent: the crate version and, when
+/// `RUSTC_VERSION` was set in the build environment, the rustc version.
+pub(crate) fn build_labels() -> HashMap<String, String> {
+    let mut labels = HashMap::new();
+    labels.insert(
+        "cloud_profiler_rust_version".to_string(),
+        env!("CARGO_PKG_VERSION").to_string(),
+    );
+    // Accept either a bare version or `rustc --version` output, keeping only
+    // the version number as label values can't contain spaces.
+    if let Some(rustc_version) = option_env!("RUSTC_VERSION").and_then(|v| {
+        v.split_whitespace()
+            .find(|p| p.starts_with(|c: char| c.is_ascii_digit()))
+    }) {
+        labels.insert("rustc_version".to_string(), rustc_version.to_string());
+    }
+    labels
+}
+
+/// Best-effort detection of the Kubernetes pod and node the process runs
+/// on. Kubernetes sets `HOSTNAME` to the pod name, and `POD_NAME` /
+/// `NODE_NAME` are the names conventionally used when exposing
+/// `metadata.name` and `spec.nodeName` through the downward API.
+pub(crate) fn kubernetes_labels() -> HashMap<String, String> {
+    let mut labels = HashMap::new();
+    if env_var("KUBERNETES_SERVICE_HOST").is_none() {
+        return labels;
+    }
+    if let Some(pod) = env_var("POD_NAME").or_else(|| env_var("HOSTNAME")) {
+        labels.insert("pod".to_string(), pod);
+    }
+    if let Some(node) = env_var("NODE_NAME") {
+        labels.insert("node".to_string(), node);
+    }
+    labels
+}
+
+/// Best-effort detection of the compute platform the process runs on, as a
+/// `platform` label: `cloud_run`, `app_engine`, `gke`, `kubernetes` (off
+/// GCP) or `gce`. No label is added when none of these is detected. A
+/// `declared` platform is used as is, without detection.
+pub(crate) async fn platform_labels(declared: Option<Platform>) -> HashMap<String, String> {
+    let mut labels = HashMap::new();
+    let platform = if let Some(declared) = declared {
+        Some(declared.as_str())
+    } else if env_var("K_SERVICE").is_some() || env_var("CLOUD_RUN_JOB").is_some() {
+        Some("cloud_run")
+    } else if env_var("GAE_SERVICE").is_some() {
+        Some("app_engine")
+    } else if env_var("KUBERNETES_SERVICE_HOST").is_some() {
+        Some(if crate::is_on_gce().await {
+            "gke"
+        } else {
+            "kubernetes"
+        })
+    } else if crate::is_on_gce().await {
+        Some("gce")
+    } else {
+        None
+    };
+    if let Some(platform) = platform {
+        labels.insert("platform".to_string(), platform.to_string());
+    }
+    labels
+}
+
+/// Best-effort `zone` and `instance` labels read from the GCE metadata
+/// server. Each label is fetched independently, one that can't be read is
+/// logged at debug level and left out, so profiling proceeds with whatever
+/// labels were obtained. A `declared` platform runs on GCP, so the metadata
+/// server is queried without probing for it first.
+pub(crate) async fn metadata_labels(declared: Option<Platform>) -> HashMap<String, String> {
+    metadata_labels_from(declared, crate::is_on_gce, metadata_value).await
+}
+
+/// `metadata_labels` with the probe for the metadata server and the fetch
+/// of a metadata value passed in.
+async fn metadata_labels_from<P, F>(
+    declared: Option<Platform>,
+    on_gce: impl FnOnce() -> P,
+    fetch: impl Fn(&'static str) -> F,
+) -> HashMap<String, String>
+where
+    P: Future<Output = bool>,
+    F: Future<Output = Result<String, String>>,
+{
+    let mut labels = HashMap::new();
+    if declared.is_none() && !on_gce().await {
+        return labels;
+    }
+    // The zone is returned as `projects/<number>/zones/<zone>`
+    match fetch("instance/zone").await {
+        Ok(zone) => match zone.rsplit('/').next().filter(|z| !z.is_empty()) {
+            Some(zone) => {
+                labels.insert("zone".to_string(), zone.to_string());
+            }
+            None => {
+                log::debug!(
+                    target: crate::log_target(),
+                    "[gcp cloud profiler] Ignoring malformed zone {:?}",
+                    zone
+                )
+            }
+        },
+        Err(e) => {
+            log::debug!(
+                target: crate::log_target(),
+                "[gcp cloud profiler] Failed to read zone: {}",
+                e
+            )
+        }
+    }
+    match fetch("instance/name").await {
+        Ok(instance) if !instance.is_empty() => {
+            labels.insert("instance".to_string(), instance);
+        }
+        Ok(_) => {}
+        Err(e) => {
+            log::debug!(
+                target: crate::log_target(),
+                "[gcp cloud profiler] Failed to read instance name: {}",
+                e
+            )
+        }
+    }
+    labels
+}
+
+async fn metadata_value(path: &str) -> Result<String, String> {
+    let host = env_var("GCE_METADATA_HOST").unwrap_or("metadata.google.internal".to_string());
+    let request = hyper::Request::get(format!("http://{}/computeMetadata/v1/{}", host, path))
+        .header("Metadata-Flavor", "Google")
+        .body(hyper::Body::empty())
+        .map_err(|e| e.to_string())?;
+    let _permit = crate::auth::METADATA_CONCURRENCY.acquire().await;
+    let fetch = async {
+        let response = hyper::Client::new()
+            .request(request)
+            .await
+            .map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("metadata server returned {}", response.status()));
+        }
+        let body = hyper::body::to_bytes(response.into_body())
+            .await
+            .map_err(|e| e.to_string())?;
+        String::from_utf8(body.to_vec()).map_err(|e| e.to_string())
+    };
+    match tokio::time::timeout(Duration::from_secs(3), fetch).await {
+        Ok(value) => value.map(|v| v.trim().to_string()),
+        Err(_) => Err("timed out".to_string()),
+    }
+}
+
+/// The `process_start_epoch` label, in seconds since the Unix epoch. On
+/// Linux this is the start time the kernel recorded for the process. Other
+/// platforms fall back to the first time a profiler in this process builds
+/// its labels, which is close to process start for the usual case of
+/// starting the profiler from `main`.
+pub(crate) fn process_start_labels() -> HashMap<String, String> {
+    static PROCESS_START: OnceLock<u64> = OnceLock::new();
+    let start = PROCESS_START.get_or_init(|| {
+        kernel_process_start().unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default()
+        })
+    });
+    let mut labels = HashMap::new();
+    labels.insert("process_start_epoch".to_string(), start.to_string());
+    labels
+}
+
+#[cfg(target_os = "linux")]
+fn kernel_process_start() -> Option<u64> {
+    let stat = std::fs::read_to_string("/proc/self/stat").ok()?;
+    let system_stat = std::fs::read_to_string("/proc/stat").ok()?;
+    // SAFETY: sysconf only reads a system configuration value
+    let ticks_per_sec = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    process_start_from(&stat, &system_stat, u64::try_from(ticks_per_sec).ok()?)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn kernel_process_start() -> Option<u64> {
+    None
+}
+
+/// The start of a process in seconds since the Unix epoch, from its
+/// `/proc/<pid>/stat` and the system's `/proc/stat`: the boot time `btime`
+/// plus the process' `starttime`, in clock ticks since boot.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn process_start_from(stat: &str, system_stat: &str, ticks_per_sec: u64) -> Option<u64> {
+    // The command name can contain spaces and parentheses, the fields after
+    // it start with the 3rd one, the state, and `starttime` is the 22nd
+    let (_, fields) = stat.rsplit_once(')')?;
+    let start_ticks: u64 = fields.split_whitespace().nth(22 - 3)?.parse().ok()?;
+    let boot_time: u64 = system_stat
+        .lines()
+        .find_map(|line| line.strip_prefix("btime "))?
+        .trim()
+        .parse()
+        .ok()?;
+    Some(boot_time + start_ticks / ticks_per_sec.max(1))
+}
+
+fn env_var(key: &str) -> Option<String> {
+    std::env::var(key).ok().filter(|v| !v.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn fetch(path: &'static str) -> Result<String, String> {
+        match path {
+            "instance/zone" => Ok("projects/123/zones/europe-west1-b".to_string()),
+            "instance/name" => Ok("instance-1".to_string()),
+            _ => Err(format!("unexpected path {}", path)),
+        }
+    }
+
+    #[tokio::test]
+    async fn declared_platform_skips_the_gce_probe() {
+        let labels = metadata_labels_from(
+            Some(Platform::CloudRun),
+            || async { panic!("probed the metadata server") },
+            fetch,
+        )
+        .await;
+        assert_eq!(labels["zone"], "europe-west1-b");
+        assert_eq!(labels["instance"], "instance-1");
+    }
+
+    #[tokio::test]
+    async fn undeclared_platform_probes_first() {
+        let labels = metadata_labels_from(None, || async { false }, fetch).await;
+        assert!(labels.is_empty());
+    }
+
+    #[test]
+    fn process_start_is_boot_time_plus_start_ticks() {
+        let stat = "4242 (my (odd) app) S 1 4242 4242 0 -1 4194560 1520 0 0 0 3 1 0 0 20 0 \
+                    4 0 123400 12345678 456 18446744073709551615";
+        let system_stat = "cpu  1 2 3 4\nbtime 1700000000\nprocesses 99\n";
+        assert_eq!(
+            process_start_from(stat, system_stat, 100),
+            Some(1_700_000_000 + 1234)
+        );
+        assert_eq!(process_start_from(stat, "cpu  1 2 3 4\n", 100), None);
+
+        #[cfg(target_os = "linux")]
+        {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+            assert!(kernel_process_start().unwrap() <= now.as_secs());
+        }
+    }
+
+    #[tokio::test]
+    async fn failing_zone_fetch_keeps_the_other_labels() {
+        let labels = metadata_labels_from(
+            None,
+            || async { true },
+            |path| async move {
+                match path {
+                    "instance/zone" => Err("metadata server returned 503".to_string()),
+                    path => fetch(path).await,
+                }
+            },
+        )
+        .await;
+        assert!(!labels.contains_key("zone"));
+        assert_eq!(labels["instance"], "instance-1");
+    }
+}
@@ -1,61 +1,171 @@
+mod archive;
+mod auth;
 mod backoff;
+mod builder;
+mod config;
+#[cfg(feature = "dedicated-thread")]
+mod dedicated;
+mod handle;
+mod labels;
+#[cfg(feature = "speedscope")]
+mod speedscope;
+#[cfg(feature = "testing")]
+pub mod testing;
+use auth::AuthTokenProvider;
 use flate2::write::GzEncoder;
 use flate2::Compression;
 use google_cloud_metadata::on_gce;
-use google_cloud_token::TokenSourceProvider;
-use google_cloudprofiler2::api::CreateProfileRequest;
-use google_cloudprofiler2::api::Deployment;
-use google_cloudprofiler2::api::Profile;
 use google_cloudprofiler2::hyper::client::HttpConnector;
 use google_cloudprofiler2::{hyper, CloudProfiler};
 use hyper_rustls::HttpsConnector;
 use pprof::protos::Message;
 use pprof::Report;
-use serde::Deserialize;
-use serde::Serialize;
-use std::collections::HashMap;
+use rand::Rng;
+use std::collections::{HashMap, VecDeque};
 use std::default::Default;
 use std::io::Write;
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant, SystemTime};
 use thiserror::Error;
 
-const SCOPES: [&str; 3] = [
-    "https://www.googleapis.com/auth/cloud-platform",
-    "https://www.googleapis.com/auth/monitoring",
-    "https://www.googleapis.com/auth/monitoring.write",
-];
+pub use backoff::BackoffReset;
+pub use builder::{
+    AnnotationProvider, CreateRequestHook, EventHook, HeapProfileProvider, ProfilerBuilder,
+    TokenProvider, UploadFilter,
+};
+pub use config::{
+    CloudProfilerConfiguration, LabelPrecedence, OnEmptyProfile, Platform, ProfileType,
+    ProfilerConfigError, ProfilerTarget,
+};
+pub use google_cloudprofiler2::api::{CreateProfileRequest, Deployment, Profile};
+pub use handle::{CycleOutcome, ProfilerEvent, ProfilerHandle, ProfilerState, ProfilerStatus};
 
-#[derive(Error, Debug)]
-enum GcpCloudProfilingError {
+#[derive(Error, Debug, Clone)]
+#[non_exhaustive]
+pub enum GcpCloudProfilingError {
+    #[error("Invalid profiler configuration: {0}")]
+    InvalidConfiguration(ProfilerConfigError),
+    #[error("The profiler is not running")]
+    ProfilerNotRunning,
+    #[error("Too many profile_now requests are already pending")]
+    TooManyProfileRequests,
+    #[error("Failed to build the HTTP client: {0}")]
+    FailedToBuildHttpClient(String),
     #[error("Failed to get auth token from gcp metadata server")]
     FailedToGetAuthToken(String),
     #[error("Failed to create new profile on gcp profiler server")]
     FailedToCreateProfile(String),
-    #[error("Failed to profile current application")]
-    FailedToProfileApplication(String),
+    /// Collecting the profile failed. The sampling rate and duration are
+    /// those of the attempted collection, `None` when the failure happened
+    /// outside of one (e.g. while collecting a heap profile).
+    ///
+    /// ```
+    /// use cloud_profiler_rust::GcpCloudProfilingError;
+    /// use std::time::Duration;
+    ///
+    /// let error = GcpCloudProfilingError::FailedToProfileApplication {
+    ///     reason: "sampler failed".to_string(),
+    ///     sampling_rate: Some(100),
+    ///     duration: Some(Duration::from_secs(10)),
+    /// };
+    /// assert_eq!(
+    ///     error.to_string(),
+    ///     "Failed to profile current application (sampling rate 100 Hz, duration 10s): sampler failed"
+    /// );
+    /// ```
+    #[error("Failed to profile current application{}: {reason}", collection_context(.sampling_rate, .duration))]
+    FailedToProfileApplication {
+        reason: String,
+        sampling_rate: Option<i32>,
+        duration: Option<Duration>,
+    },
+    #[error("A pprof profiler is already running in this process, only one profiler can run per process")]
+    ProfilerAlreadyRunning,
     #[error("Failed to build pprof data from profile")]
     FailedToBuildReport(String),
     #[error("Failed to serialize profile data for transmitting to GCP")]
     FailedToSerializeProfile(String),
     #[error("Failed to send profile data for transmitting to GCP")]
     FailedToSendProfileToGCP(String),
+    /// The profile was deliberately not uploaded, e.g. because it exceeded
+    /// `ProfilerBuilder::max_upload_delay`, or not even collected, because
+    /// the cycle was sampled out by `cycle_sampling_probability`. Unlike the
+    /// other errors this doesn't back off the next cycle.
+    #[error("Skipped the profile upload: {0}")]
+    UploadSkipped(String),
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct CloudProfilerConfiguration {
-    pub sampling_rate: i32,
+impl GcpCloudProfilingError {
+    /// A short, stable name for the kind of failure, e.g. for metrics.
+    ///
+    /// ```
+    /// use cloud_profiler_rust::GcpCloudProfilingError;
+    ///
+    /// let error = GcpCloudProfilingError::FailedToCreateProfile("503".to_string());
+    /// assert_eq!(error.category(), "create_profile");
+    /// ```
+    pub fn category(&self) -> &'static str {
+        match self {
+            GcpCloudProfilingError::InvalidConfiguration(_) => "configuration",
+            GcpCloudProfilingError::ProfilerNotRunning
+            | GcpCloudProfilingError::TooManyProfileRequests => "profiler_state",
+            GcpCloudProfilingError::FailedToBuildHttpClient(_) => "http_client",
+            GcpCloudProfilingError::FailedToGetAuthToken(_) => "auth",
+            GcpCloudProfilingError::FailedToCreateProfile(_) => "create_profile",
+            GcpCloudProfilingError::FailedToProfileApplication { .. }
+            | GcpCloudProfilingError::ProfilerAlreadyRunning
+            | GcpCloudProfilingError::FailedToBuildReport(_) => "collection",
+            GcpCloudProfilingError::FailedToSerializeProfile(_) => "serialization",
+            GcpCloudProfilingError::FailedToSendProfileToGCP(_) => "upload",
+            GcpCloudProfilingError::UploadSkipped(_) => "skipped",
+        }
+    }
+}
+
+fn collection_context(sampling_rate: &Option<i32>, duration: &Option<Duration>) -> String {
+    match (sampling_rate, duration) {
+        (Some(rate), Some(duration)) => {
+            format!(" (sampling rate {} Hz, duration {:?})", rate, duration)
+        }
+        (Some(rate), None) => format!(" (sampling rate {} Hz)", rate),
+        (None, Some(duration)) => format!(" (duration {:?})", duration),
+        (None, None) => String::new(),
+    }
 }
 
 /// This is a best effort attempt to run the GCP profiler on a rust
 /// service. This is not officially supported by Google Cloud and
 /// can run the risk of breaking at some point.
 ///
+/// Profiling only starts when running on GCE, unless
+/// `CLOUD_PROFILER_FORCE_ENABLE=1` is set, e.g. for CI pipelines that can't
+/// reach the metadata server. Valid credentials are still required, e.g.
+/// through `GOOGLE_APPLICATION_CREDENTIALS`.
+///
+/// Setting `CLOUD_PROFILER_ENABLED=0` (or `false`) turns this into a no-op
+/// returning a finished handle, taking precedence over both
+/// `CLOUD_PROFILER_FORCE_ENABLE` and `should_start`, see
+/// `ProfilerBuilder::enabled`.
+///
 /// # Example
 ///
-/// ```
-/// use cloud_profiler_rust;
-/// cloud_profiler_rust::maybe_start_profiling("my-gcp-project-id", "my-service", "v1", || { should_run_profiler() });
+/// ```no_run
+/// # fn should_run_profiler() -> bool { true }
+/// # async fn run() {
+/// cloud_profiler_rust::maybe_start_profiling(
+///     "my-gcp-project-id".to_string(),
+///     "my-service".to_string(),
+///     "v1".to_string(),
+///     || should_run_profiler(),
+///     || {
+///         let mut configuration = cloud_profiler_rust::CloudProfilerConfiguration::default();
+///         configuration.sampling_rate = 100;
+///         configuration
+///     },
+/// )
+/// .await;
+/// # }
 /// ```
 pub async fn maybe_start_profiling<F, G>(
     project_id: String,
@@ -63,206 +173,2393 @@ pub async fn maybe_start_profiling<F, G>(
     version: String,
     should_start: F,
     get_configuration: G,
-) where
+) -> ProfilerHandle
+where
     F: Fn() -> bool + Send + Sync + 'static,
     G: Fn() -> CloudProfilerConfiguration + Send + Sync + 'static,
 {
-    if !on_gce().await {
-        return;
+    let enabled = std::env::var("CLOUD_PROFILER_ENABLED")
+        .map_or(true, |v| !(v == "0" || v.eq_ignore_ascii_case("false")));
+    if !enabled {
+        log::info!(
+            target: log_target(),
+            "[gcp cloud profiler] CLOUD_PROFILER_ENABLED is off, not starting profiler..."
+        );
+        return ProfilerHandle::finished();
+    }
+    let mut builder = ProfilerBuilder::new();
+    if std::env::var("CLOUD_PROFILER_FORCE_ENABLE").is_ok_and(|v| v == "1") {
+        log::info!(
+            target: log_target(),
+            "[gcp cloud profiler] CLOUD_PROFILER_FORCE_ENABLE is set, skipping the GCE check..."
+        );
+        builder.skip_gce_check = true;
+    }
+    builder
+        .project_id(project_id)
+        .service(service)
+        .version(version)
+        .should_start(should_start)
+        .get_configuration(get_configuration)
+        .start()
+        .await
+        .unwrap_or_else(|e| {
+            log::warn!(
+                target: log_target(),
+                "[gcp cloud profiler] Not starting profiler: {}",
+                e
+            );
+            ProfilerHandle::finished()
+        })
+}
+
+/// The version of the crate this macro is expanded in, its
+/// `CARGO_PKG_VERSION`, for `ProfilerBuilder::version_from_cargo`.
+#[macro_export]
+macro_rules! cargo_version {
+    () => {
+        env!("CARGO_PKG_VERSION")
+    };
+}
+
+/// Default target of the records the crate emits through `log`.
+pub const DEFAULT_LOG_TARGET: &str = "cloud_profiler";
+
+static LOG_TARGET: OnceLock<String> = OnceLock::new();
+
+/// Sets the process-wide `log` target, when `target` is set. Only the first
+/// call has an effect, later profilers log under the target set by the
+/// first one.
+fn init_log_target(target: Option<&str>) {
+    let current = LOG_TARGET.get_or_init(|| target.unwrap_or(DEFAULT_LOG_TARGET).to_string());
+    if let Some(target) = target {
+        warn_if_ignored("log_target", current.as_str(), target);
+    }
+}
+
+/// The target of every `log` record emitted by the crate.
+pub(crate) fn log_target() -> &'static str {
+    LOG_TARGET.get().map_or(DEFAULT_LOG_TARGET, |t| t.as_str())
+}
+
+/// Warns that the process-wide `setting` asked for by a profiler is ignored,
+/// when it differs from the `current` one set by an earlier profiler.
+fn warn_if_ignored<T: PartialEq + std::fmt::Debug + ?Sized>(
+    setting: &str,
+    current: &T,
+    requested: &T,
+) {
+    if current != requested {
+        log::warn!(
+            target: log_target(),
+            "[gcp cloud profiler] {} was already set to {:?} by an earlier profiler, ignoring {:?}...",
+            setting,
+            current,
+            requested
+        );
     }
+}
+
+/// A limit on concurrent requests shared by every profiler in the process,
+/// set by the first one started.
+pub(crate) struct ConcurrencyLimit {
+    /// The builder setting the limit is configured by, for warnings.
+    setting: &'static str,
+    default: usize,
+    /// The limit in effect and the semaphore enforcing it.
+    semaphore: OnceLock<(usize, tokio::sync::Semaphore)>,
+}
 
-    let shared_should_start = Arc::new(should_start);
-    let shared_get_configuration = Arc::new(get_configuration);
-    tokio::spawn(async move {
-        // Define constants
-        let mut labels = HashMap::new();
-        labels.insert("language".to_string(), "go".to_string());
-        labels.insert("version".to_string(), version.clone());
-        let deployment = Some(Deployment {
-            project_id: Some(project_id),
-            target: Some(service.clone()),
-            labels: Some(labels),
+impl ConcurrencyLimit {
+    pub(crate) const fn new(setting: &'static str, default: usize) -> Self {
+        ConcurrencyLimit {
+            setting,
+            default,
+            semaphore: OnceLock::new(),
+        }
+    }
+
+    /// Sets the limit to `limit`, or the default when it is `None`, and
+    /// returns the limit in effect. Only the first call has an effect, a
+    /// later different `limit` is ignored with a warning.
+    pub(crate) fn init(&self, limit: Option<usize>) -> usize {
+        let (current, _) = self.semaphore.get_or_init(|| {
+            let limit = limit.unwrap_or(self.default).max(1);
+            (limit, tokio::sync::Semaphore::new(limit))
         });
+        if let Some(limit) = limit {
+            warn_if_ignored(self.setting, current, &limit.max(1));
+        }
+        *current
+    }
+
+    /// Waits for a permit to make a request.
+    pub(crate) async fn acquire(&self) -> Option<tokio::sync::SemaphorePermit<'_>> {
+        self.init(None);
+        let (_, semaphore) = self.semaphore.get()?;
+        semaphore.acquire().await.ok()
+    }
+}
+
+/// Envelope of the randomized backoff between failed cycles.
+const BACKOFF_MIN_SECS: f64 = 60.0;
+const BACKOFF_MAX_SECS: f64 = 3600.0;
+const BACKOFF_MULTIPLIER: f64 = 1.3;
 
-        let mut backoff_provider = backoff::Backoff::new(60.0, 3600.0, 1.3);
-        let mut retry_back_off = None;
+/// Backoff and retry state of a single profiling loop. Every loop owns its
+/// own, so a deployment that keeps failing doesn't slow down the others.
+struct LoopBackoff {
+    backoff: backoff::Backoff,
+    backoff_reset: BackoffReset,
+    retry_budget: Option<backoff::RetryBudget>,
+    degrade_after: u32,
+    consecutive_failures: u32,
+    /// Seconds to wait before the next cycle, set by a failed one.
+    retry_back_off: Option<f64>,
+}
+
+impl LoopBackoff {
+    fn new(
+        retry_budget: Option<(u32, Duration)>,
+        backoff_reset: BackoffReset,
+        degrade_after: u32,
+    ) -> Self {
+        LoopBackoff {
+            backoff: backoff::Backoff::new(BACKOFF_MIN_SECS, BACKOFF_MAX_SECS, BACKOFF_MULTIPLIER),
+            backoff_reset,
+            retry_budget: retry_budget
+                .map(|(max_retries, window)| backoff::RetryBudget::new(max_retries, window)),
+            degrade_after,
+            consecutive_failures: 0,
+            retry_back_off: None,
+        }
+    }
+
+    /// Records a cycle that uploaded a profile.
+    fn on_success(&mut self, cycle: u64) {
+        if self.consecutive_failures >= self.degrade_after && self.degrade_after > 0 {
+            log::info!(
+                target: log_target(),
+                "[gcp cloud profiler] [cycle {}] Recovered after {} failed cycles, restoring cadence...",
+                cycle,
+                self.consecutive_failures
+            );
+        }
+        self.consecutive_failures = 0;
+        self.backoff.on_success(self.backoff_reset);
+        self.retry_back_off = None;
+    }
+
+    /// Records a cycle whose upload was skipped. Nothing went wrong with
+    /// the server, so the cadence is kept.
+    fn on_skipped(&mut self) {
+        self.consecutive_failures = 0;
+        self.retry_back_off = None;
+    }
+
+    /// Records a failed cycle, returning the seconds to wait before the
+    /// next one.
+    fn on_failure(&mut self, cycle: u64) -> f64 {
+        let mut rbo = self.backoff.next_backoff();
+        if let Some(budget) = self.retry_budget.as_mut() {
+            let refill = budget.acquire();
+            if !refill.is_zero() {
+                log::warn!(
+                    target: log_target(),
+                    "[gcp cloud profiler] [cycle {}] Retry budget exhausted...",
+                    cycle
+                );
+                rbo = rbo.max(refill.as_secs_f64());
+            }
+        }
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+        if let Some(period) = backoff::degraded_period(
+            self.consecutive_failures,
+            self.degrade_after,
+            Duration::from_secs_f64(BACKOFF_MIN_SECS),
+            Duration::from_secs_f64(BACKOFF_MAX_SECS),
+        ) {
+            log::warn!(
+                target: log_target(),
+                "[gcp cloud profiler] [cycle {}] {} cycles failed in a row, slowing down to one cycle per {:?}...",
+                cycle,
+                self.consecutive_failures,
+                period
+            );
+            rbo = rbo.max(period.as_secs_f64());
+        }
+        self.retry_back_off = Some(rbo);
+        rbo
+    }
+}
+
+pub(crate) async fn start_profiling(
+    builder: ProfilerBuilder,
+    configuration: CloudProfilerConfiguration,
+) -> Result<ProfilerHandle, ProfilerConfigError> {
+    init_log_target(builder.log_target.as_deref());
+    auth::METADATA_CONCURRENCY.init(builder.metadata_concurrency);
+    UPLOAD_CONCURRENCY.init(builder.upload_concurrency);
+    let endpoint = builder.target.endpoint()?;
+    let deployment = build_deployment(&builder).await?;
+    // Explicit credentials are typically used off GCP, where the check would
+    // always fail, see `ProfilerBuilder::credentials_file`
+    let skip_gce_check = builder.skip_gce_check
+        || builder.platform.is_some()
+        || builder.credentials_file.is_some()
+        || builder.token_provider.is_some();
+    if !skip_gce_check && !is_on_gce().await {
+        return Ok(ProfilerHandle::finished());
+    }
+    let handle = ProfilerHandle::new();
+    PROFILER_PID.get_or_init(std::process::id);
+
+    let parent = parent_resource(&builder);
+    log_startup_summary(
+        &builder,
+        &configuration,
+        &deployment,
+        &parent,
+        &endpoint,
+        skip_gce_check,
+    );
+    handle.set_deployment(deployment.clone(), parent.clone());
+    let metadata_labels_enabled = builder.auto_labels && builder.deployment.is_none();
+    let ProfilerBuilder {
+        should_start: shared_should_start,
+        get_configuration: shared_get_configuration,
+        startup_jitter,
+        cycle_jitter,
+        align_cycles,
+        platform,
+        labels: user_labels,
+        label_precedence,
+        metadata_refresh,
+        credentials_file,
+        token_provider,
+        healthy_within,
+        heap_profile,
+        max_lifetime,
+        retry_budget,
+        backoff_reset,
+        degrade_after,
+        http2_only,
+        max_outstanding,
+        recent_outcomes,
+        gzip_capacity,
+        max_upload_delay,
+        customize_create_request,
+        override_duration,
+        pool_idle_timeout,
+        pool_max_idle_per_host,
+        client_max_age,
+        connector_attempts,
+        max_labels,
+        should_upload,
+        profile_annotations,
+        archive,
+        on_event,
+        #[cfg(feature = "speedscope")]
+        speedscope_dir,
+        #[cfg(feature = "dedicated-thread")]
+        dedicated_thread,
+        #[cfg(feature = "dedicated-thread")]
+        cpu_affinity,
+        ..
+    } = builder;
+    let loop_handle = handle.clone();
+    let mut requests = handle.profile_requests();
+    let profiling_loop = async move {
+        let mut deployment = deployment;
+        let mut metadata_detected_at = Instant::now();
+        // Spread out the first create call across replicas
+        if let Some(max_jitter) = startup_jitter.filter(|j| !j.is_zero()) {
+            let jitter = rand::thread_rng().gen_range(Duration::ZERO..max_jitter);
+            tokio::time::sleep(jitter).await;
+        }
+
+        let mut client = GcpClient {
+            auth: AuthTokenProvider::new(credentials_file),
+            token_provider,
+            http2_only,
+            cycle: 0,
+            outstanding: VecDeque::new(),
+            max_outstanding,
+            profile_type: None,
+            uploaded_bytes: 0,
+            gzip_capacity,
+            collected_at: Instant::now(),
+            max_upload_delay,
+            customize_create_request,
+            pool_idle_timeout,
+            pool_max_idle_per_host,
+            http: None,
+            endpoint,
+            client_max_age,
+            connector_attempts,
+            should_upload,
+            profile_annotations,
+            archive,
+            on_event,
+            #[cfg(feature = "speedscope")]
+            speedscope_dir,
+        };
+        let mut backoff = LoopBackoff::new(retry_budget, backoff_reset, degrade_after);
+        let mut waiting = Vec::new();
         loop {
-            if !shared_should_start() {
+            if is_forked_child() {
+                log::warn!(
+                    target: log_target(),
+                    "[gcp cloud profiler] Running in a forked child process, stopping..."
+                );
+                return;
+            }
+            while let Ok(request) = requests.try_recv() {
+                waiting.push(request);
+            }
+            if waiting.is_empty() && (loop_handle.is_paused() || !shared_should_start()) {
+                loop_handle.mark_healthy();
                 // Sleep for 60 seconds
-                tokio::time::sleep(std::time::Duration::new(60, 0)).await;
+                sleep_or_request(Duration::new(60, 0), &mut requests, &mut waiting).await;
                 continue;
             }
-            if let Some(rbo) = retry_back_off {
-                println!("[gcp cloud profiler] Retrying in {:.3} seconds...", rbo);
-                tokio::time::sleep(std::time::Duration::from_secs_f64(rbo)).await;
-            } else {
-                // Reset backoff if we're succeeding
-                backoff_provider = backoff::Backoff::new(60.0, 3600.0, 1.3);
-                retry_back_off = None;
+            client.cycle += 1;
+            if let Some(rbo) = backoff.retry_back_off.filter(|_| waiting.is_empty()) {
+                log::warn!(
+                    target: log_target(),
+                    "[gcp cloud profiler] [cycle {}] Retrying in {:.3} seconds...",
+                    client.cycle,
+                    rbo
+                );
+                sleep_or_request(Duration::from_secs_f64(rbo), &mut requests, &mut waiting).await;
+            }
+            if let Some(interval) = align_cycles.filter(|_| waiting.is_empty()) {
+                let wait = until_aligned(SystemTime::now(), interval);
+                sleep_or_request(wait, &mut requests, &mut waiting).await;
             }
 
-            // Make a request to GCP profiler server to generate
-            // a new profile instance
-            let profile = match create_profile(&deployment).await {
-                Ok(profile) => profile,
-                Err(e) => {
-                    println!("[gcp cloud profiler] Error creating profile: {:?}", e);
-                    retry_back_off = Some(backoff_provider.next_backoff());
-                    continue;
-                }
-            };
-            let profile_duration = match profile.duration {
-                Some(d) => std::time::Duration::new(
-                    d.num_seconds() as u64,
-                    (d.num_milliseconds() as u32) * 1000,
-                ),
-                None => {
-                    println!("[gcp cloud profiler] Profile missing duration...");
-                    retry_back_off = Some(backoff_provider.next_backoff());
-                    continue;
+            let refresh_requested = loop_handle.take_metadata_refresh();
+            let refresh_due = metadata_refresh.is_some_and(|i| metadata_detected_at.elapsed() >= i);
+            if metadata_labels_enabled && (refresh_requested || refresh_due) {
+                if let Some(deployment) = deployment.as_mut() {
+                    refresh_metadata_labels(
+                        deployment,
+                        platform,
+                        &user_labels,
+                        label_precedence,
+                        max_labels,
+                    )
+                    .await;
+                    loop_handle.set_deployment(Some(deployment.clone()), parent.clone());
                 }
-            };
+                metadata_detected_at = Instant::now();
+            }
 
-            // Profile application using pprof based on the duration
-            // specified by the GCP profiler server
             let configuration = shared_get_configuration();
-            let report = match do_profile(profile_duration, &configuration).await {
-                Ok(report) => report,
+            loop_handle.set_active_profile_types(offered_profile_types(
+                &configuration,
+                heap_profile.as_ref(),
+            ));
+            client.profile_type = None;
+            client.uploaded_bytes = 0;
+            let started = Instant::now();
+            let result = run_one_cycle(
+                &mut client,
+                &parent,
+                &cycle_deployment(&deployment, &configuration, max_labels),
+                &configuration,
+                heap_profile.as_ref(),
+                override_duration,
+                !waiting.is_empty(),
+            )
+            .await;
+            if recent_outcomes > 0 {
+                let outcome = CycleOutcome {
+                    cycle: client.cycle,
+                    profile_type: client.profile_type.clone(),
+                    error: result.as_ref().err().map(|e| e.to_string()),
+                    duration: started.elapsed(),
+                    bytes: if result.is_ok() {
+                        client.uploaded_bytes
+                    } else {
+                        0
+                    },
+                    finished_at: SystemTime::now(),
+                };
+                loop_handle.record_outcome(outcome, recent_outcomes);
+            }
+            for request in waiting.drain(..) {
+                let _ = request.send(result.clone());
+            }
+            match result {
+                Ok(_) => {
+                    loop_handle.record_success();
+                    backoff.on_success(client.cycle);
+                    let (floor, ceiling) = cycle_jitter;
+                    if !ceiling.is_zero() {
+                        let jitter = rand::thread_rng().gen_range(floor..=ceiling);
+                        sleep_or_request(jitter, &mut requests, &mut waiting).await;
+                    }
+                }
+                Err(e @ GcpCloudProfilingError::UploadSkipped(_)) => {
+                    // Don't report the cycle as healthy either
+                    loop_handle.set_last_error(e);
+                    backoff.on_skipped();
+                }
                 Err(e) => {
-                    println!("[gcp cloud profiler] Error profiling: {:?}", e);
-                    retry_back_off = Some(backoff_provider.next_backoff());
-                    continue;
+                    let reason = e.category();
+                    loop_handle.set_last_error(e);
+                    let rbo = backoff.on_failure(client.cycle);
+                    client.emit(ProfilerEvent::BackingOff {
+                        seconds: rbo,
+                        reason: reason.to_string(),
+                    });
                 }
-            };
-            // Send profiled data to GCP profiler server
-            if let Err(e) = update_gcp_profile_server(report, profile).await {
-                println!("[gcp cloud profiler] Error updating profile: {:?}", e);
-                retry_back_off = Some(backoff_provider.next_backoff());
-                continue;
             }
         }
-    });
-}
+    };
 
-async fn get_hub() -> Result<CloudProfiler<HttpsConnector<HttpConnector>>, GcpCloudProfilingError> {
-    // Auth: Re-fetch auth token on every loop just incase we are
-    //       using GCP Metadata server to get the token.
-    let token = get_auth_token().await?;
-    // Create client for communicating with GCP profiler server
-    Ok(CloudProfiler::new(
-        hyper::Client::builder().build(
-            hyper_rustls::HttpsConnectorBuilder::new()
-                .with_native_roots()
-                .https_or_http()
-                .enable_http1()
-                .build(),
-        ),
-        token,
-    ))
+    let control = handle.clone();
+    let task = async move {
+        let _running = RunningLoop::enter();
+        let watchdog = async {
+            match healthy_within {
+                Some(healthy_within) => watchdog(&control, healthy_within).await,
+                None => std::future::pending().await,
+            }
+        };
+        let lifetime = async {
+            match max_lifetime {
+                Some(max_lifetime) => tokio::time::sleep(max_lifetime).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            _ = control.stopped() => {}
+            _ = profiling_loop => {}
+            _ = watchdog => {}
+            _ = lifetime => {
+                log::info!(
+                    target: log_target(),
+                    "[gcp cloud profiler] Reached max lifetime of {:?}, stopping...",
+                    max_lifetime
+                );
+                control.stop();
+            }
+        }
+        control.mark_finished();
+    };
+    #[cfg(feature = "dedicated-thread")]
+    if dedicated_thread {
+        if !dedicated::spawn(task, cpu_affinity) {
+            handle.mark_finished();
+        }
+        return Ok(handle);
+    }
+    tokio::spawn(task);
+    Ok(handle)
 }
 
-async fn get_auth_token() -> Result<String, GcpCloudProfilingError> {
-    let tsp = google_cloud_auth::token::DefaultTokenSourceProvider::new(
-        google_cloud_auth::project::Config {
-            audience: None,
-            scopes: Some(&SCOPES),
-            sub: None,
+/// Logs the effective configuration once, right before the profiling loop
+/// starts, with the `configuration` validated by `start`. Credentials are
+/// only described, never logged.
+fn log_startup_summary(
+    builder: &ProfilerBuilder,
+    configuration: &CloudProfilerConfiguration,
+    deployment: &Option<Deployment>,
+    parent: &str,
+    endpoint: &str,
+    skipped_gce_check: bool,
+) {
+    let profile_types: Vec<String> =
+        offered_profile_types(configuration, builder.heap_profile.as_ref())
+            .iter()
+            .map(|profile_type| profile_type.as_str().to_string())
+            .collect();
+    let credentials = match (&builder.token_provider, &builder.credentials_file) {
+        (Some(_), _) => "token provider".to_string(),
+        (None, Some(path)) => format!("credentials file {}", path.display()),
+        (None, None) => "default credentials".to_string(),
+    };
+    let target = deployment.as_ref().and_then(|d| d.target.as_deref());
+    let version = deployment
+        .as_ref()
+        .and_then(|d| d.labels.as_ref())
+        .and_then(|labels| labels.get("version"));
+    log::info!(
+        target: log_target(),
+        "[gcp cloud profiler] Starting: parent={} service={} version={} endpoint={} \
+         profile_types={:?} sampling_rate={} backoff={}s..{}s x{} gce={} auth={}",
+        parent,
+        target.unwrap_or("<none>"),
+        version.map_or("<none>", |v| v.as_str()),
+        endpoint,
+        profile_types,
+        configuration.sampling_rate,
+        BACKOFF_MIN_SECS,
+        BACKOFF_MAX_SECS,
+        BACKOFF_MULTIPLIER,
+        if skipped_gce_check {
+            "check skipped"
+        } else {
+            "yes"
         },
+        credentials
+    );
+}
+
+pub(crate) async fn profile_once(
+    builder: ProfilerBuilder,
+    configuration: CloudProfilerConfiguration,
+) -> Result<String, GcpCloudProfilingError> {
+    init_log_target(builder.log_target.as_deref());
+    auth::METADATA_CONCURRENCY.init(builder.metadata_concurrency);
+    UPLOAD_CONCURRENCY.init(builder.upload_concurrency);
+    let deployment = build_deployment(&builder)
+        .await
+        .map_err(GcpCloudProfilingError::InvalidConfiguration)?;
+    let endpoint = builder
+        .target
+        .endpoint()
+        .map_err(GcpCloudProfilingError::InvalidConfiguration)?;
+    let parent = parent_resource(&builder);
+    let mut client = GcpClient {
+        auth: AuthTokenProvider::new(builder.credentials_file),
+        token_provider: builder.token_provider,
+        http2_only: builder.http2_only,
+        cycle: 1,
+        outstanding: VecDeque::new(),
+        max_outstanding: 0,
+        profile_type: None,
+        uploaded_bytes: 0,
+        gzip_capacity: builder.gzip_capacity,
+        collected_at: Instant::now(),
+        max_upload_delay: builder.max_upload_delay,
+        customize_create_request: builder.customize_create_request,
+        pool_idle_timeout: builder.pool_idle_timeout,
+        pool_max_idle_per_host: builder.pool_max_idle_per_host,
+        http: None,
+        endpoint,
+        client_max_age: builder.client_max_age,
+        connector_attempts: builder.connector_attempts,
+        should_upload: builder.should_upload,
+        profile_annotations: builder.profile_annotations,
+        archive: builder.archive,
+        on_event: builder.on_event,
+        #[cfg(feature = "speedscope")]
+        speedscope_dir: builder.speedscope_dir,
+    };
+    run_one_cycle(
+        &mut client,
+        &parent,
+        &cycle_deployment(&deployment, &configuration, builder.max_labels),
+        &configuration,
+        builder.heap_profile.as_ref(),
+        builder.override_duration,
+        true,
     )
     .await
-    .map_err(|e| GcpCloudProfilingError::FailedToGetAuthToken(e.to_string()))?;
-    let token = tsp
-        .token_source()
-        .token()
+}
+
+async fn build_deployment(
+    builder: &ProfilerBuilder,
+) -> Result<Option<Deployment>, ProfilerConfigError> {
+    if let Some(deployment) = &builder.deployment {
+        return Ok(Some(deployment.clone()));
+    }
+    if builder.target.without_deployment {
+        return Ok(None);
+    }
+    let mut labels = HashMap::new();
+    if builder.auto_labels {
+        labels.extend(labels::build_labels());
+        labels.extend(labels::kubernetes_labels());
+        labels.extend(labels::platform_labels(builder.platform).await);
+        labels.extend(labels::metadata_labels(builder.platform).await);
+    }
+    if builder.process_start_label {
+        labels.extend(labels::process_start_labels());
+    }
+    let mut labels = builder.label_precedence.merge(labels, &builder.labels);
+    labels.insert("language".to_string(), "go".to_string());
+    labels.insert(
+        "version".to_string(),
+        builder.target.version.clone().unwrap_or_default(),
+    );
+    if labels.len() > builder.max_labels {
+        if builder.reject_excess_labels {
+            return Err(ProfilerConfigError::TooManyLabels {
+                count: labels.len(),
+                limit: builder.max_labels,
+            });
+        }
+        // Drop automatically detected labels first, then user labels
+        drop_excess_labels(&mut labels, builder.max_labels, |key| {
+            builder.labels.contains_key(key)
+        });
+    }
+    Ok(Some(Deployment {
+        project_id: Some(
+            builder
+                .target
+                .deployment_project_id
+                .clone()
+                .filter(|p| !p.is_empty())
+                .or_else(|| builder.target.project_id.clone())
+                .unwrap_or_default(),
+        ),
+        target: Some(builder.target.service.clone().unwrap_or_default()),
+        labels: Some(labels),
+    }))
+}
+
+/// Re-detects the metadata server labels of `deployment`, keeping the
+/// previous value of any label that can't be read this time.
+async fn refresh_metadata_labels(
+    deployment: &mut Deployment,
+    platform: Option<Platform>,
+    user_labels: &HashMap<String, String>,
+    precedence: LabelPrecedence,
+    max_labels: usize,
+) {
+    let detected = labels::metadata_labels(platform).await;
+    let labels = deployment.labels.get_or_insert_with(HashMap::new);
+    for (key, value) in detected {
+        if precedence == LabelPrecedence::UserWins && user_labels.contains_key(&key) {
+            continue;
+        }
+        if labels.get(&key) != Some(&value) {
+            log::info!(
+                target: log_target(),
+                "[gcp cloud profiler] Label {} changed to {}",
+                key,
+                value
+            );
+            labels.insert(key, value);
+        }
+    }
+    drop_excess_labels(labels, max_labels, |key| user_labels.contains_key(key));
+}
+
+/// Drops labels until at most `limit` remain, labels for which `keep_first`
+/// returns true last, and never `language` and `version` which the server
+/// relies on.
+fn drop_excess_labels(
+    labels: &mut HashMap<String, String>,
+    limit: usize,
+    keep_first: impl Fn(&str) -> bool,
+) {
+    if labels.len() <= limit {
+        return;
+    }
+    let mut droppable: Vec<String> = labels
+        .keys()
+        .filter(|k| *k != "language" && *k != "version")
+        .cloned()
+        .collect();
+    droppable.sort_by_key(|k| (keep_first(k), k.clone()));
+    droppable.truncate(labels.len() - limit);
+    for key in &droppable {
+        labels.remove(key);
+    }
+    log::warn!(
+        target: log_target(),
+        "[gcp cloud profiler] More than {} labels, dropping {:?}...",
+        limit,
+        droppable
+    );
+}
+
+/// The deployment for a cycle, with the labels from its configuration merged
+/// into the labels fixed at startup. Excess labels are always dropped here,
+/// preferring to keep the per-cycle ones.
+fn cycle_deployment(
+    deployment: &Option<Deployment>,
+    configuration: &CloudProfilerConfiguration,
+    max_labels: usize,
+) -> Option<Deployment> {
+    let mut deployment = deployment.clone()?;
+    if !configuration.labels.is_empty() {
+        let labels = deployment.labels.get_or_insert_with(HashMap::new);
+        for (key, value) in &configuration.labels {
+            if key != "language" && key != "version" {
+                labels.insert(key.clone(), value.clone());
+            }
+        }
+        drop_excess_labels(labels, max_labels, |key| {
+            configuration.labels.contains_key(key)
+        });
+    }
+    Some(deployment)
+}
+
+/// The resource profiles of `project_id` are created under, the only
+/// place resource names are constructed.
+///
+/// ```
+/// assert_eq!(cloud_profiler_rust::profile_parent("my-project"), "projects/my-project");
+/// ```
+pub fn profile_parent(project_id: &str) -> String {
+    format!("projects/{}", project_id)
+}
+
+fn parent_resource(builder: &ProfilerBuilder) -> String {
+    match &builder.target.parent {
+        Some(parent) => parent.clone(),
+        None => profile_parent(
+            builder
+                .target
+                .project_id
+                .as_deref()
+                .filter(|p| !p.is_empty())
+                .or_else(|| {
+                    builder
+                        .deployment
+                        .as_ref()
+                        .and_then(|d| d.project_id.as_deref())
+                })
+                .unwrap_or_default(),
+        ),
+    }
+}
+
+/// Time from `now` until the next multiple of `interval` since the Unix
+/// epoch, zero when `now` is on a boundary or before the epoch.
+fn until_aligned(now: SystemTime, interval: Duration) -> Duration {
+    let Ok(since_epoch) = now.duration_since(SystemTime::UNIX_EPOCH) else {
+        return Duration::ZERO;
+    };
+    let interval = interval.as_nanos().max(1);
+    let remainder = since_epoch.as_nanos() % interval;
+    if remainder == 0 {
+        return Duration::ZERO;
+    }
+    // Below `interval`, which came from a `Duration`, so it fits
+    let wait = interval - remainder;
+    Duration::new((wait / 1_000_000_000) as u64, (wait % 1_000_000_000) as u32)
+}
+
+/// Sleeps for `duration`, cutting the sleep short when a `profile_now`
+/// request arrives.
+async fn sleep_or_request(
+    duration: Duration,
+    requests: &mut tokio::sync::mpsc::Receiver<handle::ProfileRequest>,
+    waiting: &mut Vec<handle::ProfileRequest>,
+) {
+    tokio::select! {
+        _ = tokio::time::sleep(duration) => {}
+        Some(request) = requests.recv() => waiting.push(request),
+    }
+}
+
+/// Warns once per `healthy_within` window while the profiler has not been
+/// healthy (uploaded a profile, or been disabled) within that window.
+async fn watchdog(handle: &ProfilerHandle, healthy_within: Duration) {
+    let started = Instant::now();
+    loop {
+        let deadline = handle.last_healthy().unwrap_or(started) + healthy_within;
+        if Instant::now() < deadline {
+            tokio::time::sleep_until(deadline.into()).await;
+            continue;
+        }
+        log::warn!(
+            target: log_target(),
+            "[gcp cloud profiler] No profile has been uploaded in the last {:?}, check the errors above...",
+            healthy_within
+        );
+        tokio::time::sleep(healthy_within).await;
+    }
+}
+
+pub(crate) async fn is_on_gce() -> bool {
+    let _permit = auth::METADATA_CONCURRENCY.acquire().await;
+    on_gce().await
+}
+
+/// Runs a single create, collect and upload cycle against the GCP profiler
+/// server, returning the name of the profile that was uploaded. Profiles are
+/// collected for `override_duration` when set, and for the duration
+/// requested by the server otherwise.
+///
+/// A `requested` cycle, run for `profile_now`, first completes all the
+/// outstanding profiles and then always collects a new one, so it answers
+/// with a profile of its own.
+///
+/// All waiting is done through `tokio::time`, so the cycle can be driven
+/// deterministically with `tokio::time::pause` and `tokio::time::advance`,
+/// except for the collection window itself, see `do_profile`.
+async fn run_one_cycle(
+    client: &mut GcpClient,
+    parent: &str,
+    deployment: &Option<Deployment>,
+    configuration: &CloudProfilerConfiguration,
+    heap_profile: Option<&HeapProfileProvider>,
+    override_duration: Option<Duration>,
+    requested: bool,
+) -> Result<String, GcpCloudProfilingError> {
+    let cycle = client.cycle;
+    while let Some(result) = complete_outstanding(client).await {
+        let result = result.map_err(|e| {
+            log::error!(
+                target: log_target(),
+                "[gcp cloud profiler] [cycle {}] Error completing outstanding profile: {:?}",
+                cycle,
+                e
+            );
+            e
+        });
+        if !requested {
+            return result;
+        }
+    }
+    let profile_types = offered_profile_types(configuration, heap_profile);
+
+    // Make a request to GCP profiler server to generate
+    // a new profile instance
+    let (stop_background, background_stopped) = std::sync::mpsc::channel::<()>();
+    let background = async {
+        if !configuration.continuous {
+            return None;
+        }
+        let running = RUNNING_LOOPS.load(Ordering::Relaxed);
+        if running > 1 {
+            // Sampling through the long poll would hold the sampler, and
+            // the other loops' windows, for the whole wait
+            log::warn!(
+                target: log_target(),
+                "[gcp cloud profiler] [cycle {}] {} profilers are running, not sampling while waiting for the server...",
+                cycle,
+                running
+            );
+            return None;
+        }
+        let report = do_profile_until(None, configuration, cycle, background_stopped).await;
+        report
+            .map_err(|e| {
+                log::error!(
+                    target: log_target(),
+                    "[gcp cloud profiler] [cycle {}] Error profiling while waiting for the server: {:?}",
+                    cycle,
+                    e
+                );
+            })
+            .ok()
+    };
+    let create = async {
+        let profile = create_profile(client, parent, deployment, &profile_types).await;
+        drop(stop_background);
+        profile
+    };
+    let (profile, background) = tokio::join!(create, background);
+    let profile = profile.map_err(|e| {
+        log::error!(
+            target: log_target(),
+            "[gcp cloud profiler] [cycle {}] Error creating profile: {:?}",
+            cycle,
+            e
+        );
+        e
+    })?;
+    let name = profile.name.clone().unwrap_or_default();
+    client.profile_type = profile.profile_type.clone();
+
+    let is_heap = profile
+        .profile_type
+        .as_deref()
+        .is_some_and(|t| t.eq_ignore_ascii_case(ProfileType::Heap.as_str()));
+    if let Some(heap_profile) = heap_profile.filter(|_| is_heap) {
+        let content = heap_profile().map_err(|e| {
+            log::error!(
+                target: log_target(),
+                "[gcp cloud profiler] [cycle {}] Error collecting heap profile: {:?}",
+                cycle,
+                e
+            );
+            GcpCloudProfilingError::FailedToProfileApplication {
+                reason: e,
+                sampling_rate: None,
+                duration: None,
+            }
+        })?;
+        client.collected_at = Instant::now();
+        return upload_profile_bytes(client, &content, profile)
+            .await
+            .map(|()| name)
+            .map_err(|e| log_upload_error(cycle, e));
+    }
+
+    // `profile.duration` is a span sent by the server, not a point in time,
+    // so converting it is unaffected by the local clock. The window itself is
+    // then measured on the monotonic clock (`Instant`, `recv_timeout`, tokio
+    // timers), as is every other sleep and deadline in the crate: system
+    // clock jumps (NTP corrections, VM migrations) can't stretch or cut a
+    // cycle short. Wall clock time is only read for labels.
+    let server_duration = profile.duration.map(|d| d.to_std());
+    let profile_duration = match override_duration.map(Ok).or(server_duration) {
+        Some(Ok(d)) => d,
+        Some(Err(_)) => {
+            log::warn!(
+                target: log_target(),
+                "[gcp cloud profiler] [cycle {}] Profile has a negative duration...",
+                cycle
+            );
+            return Err(GcpCloudProfilingError::FailedToCreateProfile(
+                "GCP profile had a negative duration...".to_string(),
+            ));
+        }
+        None => {
+            log::warn!(
+                target: log_target(),
+                "[gcp cloud profiler] [cycle {}] Profile missing duration...",
+                cycle
+            );
+            return Err(GcpCloudProfilingError::FailedToCreateProfile(
+                "GCP profile did not contain a duration...".to_string(),
+            ));
+        }
+    };
+
+    let sampling_probability = match configuration.cycle_sampling_probability {
+        p if p.is_nan() => 1.0,
+        p => p.clamp(0.0, 1.0),
+    };
+    if !requested && !rand::thread_rng().gen_bool(sampling_probability) {
+        log::debug!(
+            target: log_target(),
+            "[gcp cloud profiler] [cycle {}] Sampled out, releasing {}",
+            cycle,
+            name
+        );
+        release_profile(client, profile).await.map_err(|e| {
+            log::error!(
+                target: log_target(),
+                "[gcp cloud profiler] [cycle {}] Error releasing skipped profile: {:?}",
+                cycle,
+                e
+            );
+            e
+        })?;
+        return Err(GcpCloudProfilingError::UploadSkipped(
+            "sampled out by cycle_sampling_probability".to_string(),
+        ));
+    }
+
+    // Profile application using pprof based on the duration
+    // specified by the GCP profiler server
+    let mut report = collect_windows(profile_duration, configuration, cycle)
         .await
-        .map_err(|e| GcpCloudProfilingError::FailedToGetAuthToken(e.to_string()))?;
-    Ok(token.trim_start_matches("Bearer ").to_string())
+        .map_err(|e| {
+            log::error!(
+                target: log_target(),
+                "[gcp cloud profiler] [cycle {}] Error profiling: {:?}",
+                cycle,
+                e
+            );
+            e
+        })?;
+    if let Some(background) = background {
+        merge_report(&mut report, background);
+    }
+    client.collected_at = Instant::now();
+    if report.data.is_empty() && configuration.on_empty_profile == OnEmptyProfile::Skip {
+        log::debug!(
+            target: log_target(),
+            "[gcp cloud profiler] [cycle {}] Collected no samples, skipping upload",
+            cycle
+        );
+        let reason = "collected no samples".to_string();
+        client.emit(ProfilerEvent::ProfileSkipped {
+            profile: name,
+            reason: reason.clone(),
+        });
+        return Err(GcpCloudProfilingError::UploadSkipped(reason));
+    }
+    #[cfg(feature = "speedscope")]
+    if let Some(dir) = &client.speedscope_dir {
+        let id = name.rsplit('/').next().unwrap_or_default();
+        match speedscope::write_report(&report, dir, id) {
+            Ok(path) => log::debug!(
+                target: log_target(),
+                "[gcp cloud profiler] [cycle {}] Wrote speedscope profile to {}",
+                cycle,
+                path.display()
+            ),
+            Err(e) => log::error!(
+                target: log_target(),
+                "[gcp cloud profiler] [cycle {}] Error writing speedscope profile: {:?}",
+                cycle,
+                e
+            ),
+        }
+    }
+    // Send profiled data to GCP profiler server
+    update_gcp_profile_server(client, report, profile)
+        .await
+        .map(|()| name)
+        .map_err(|e| log_upload_error(cycle, e))
+}
+
+/// Logs a failed upload, except for a skipped one, which is logged at debug
+/// level where it is skipped.
+fn log_upload_error(cycle: u64, e: GcpCloudProfilingError) -> GcpCloudProfilingError {
+    if !matches!(e, GcpCloudProfilingError::UploadSkipped(_)) {
+        log::error!(
+            target: log_target(),
+            "[gcp cloud profiler] [cycle {}] Error updating profile: {:?}",
+            cycle,
+            e
+        );
+    }
+    e
+}
+
+/// The profile types offered to the server for a cycle run with
+/// `configuration`.
+fn offered_profile_types(
+    configuration: &CloudProfilerConfiguration,
+    heap_profile: Option<&HeapProfileProvider>,
+) -> Vec<ProfileType> {
+    // Heap profiles can only be offered when there is something to upload
+    let mut profile_types = configuration.profile_types.clone();
+    match heap_profile {
+        Some(_) if !profile_types.contains(&ProfileType::Heap) => {
+            profile_types.push(ProfileType::Heap)
+        }
+        Some(_) => {}
+        None => profile_types.retain(|profile_type| *profile_type != ProfileType::Heap),
+    }
+    profile_types
+}
+
+/// Everything needed to talk to the GCP profiler server.
+struct GcpClient {
+    auth: AuthTokenProvider,
+    /// Used instead of `auth` when set.
+    token_provider: Option<TokenProvider>,
+    http2_only: bool,
+    /// Sequential id of the current cycle, included in its log lines.
+    cycle: u64,
+    /// Profiles created on the server whose upload failed, by name, oldest
+    /// first. Completed before any new profile is created.
+    outstanding: VecDeque<(String, Profile, Instant)>,
+    max_outstanding: usize,
+    /// The type of the profile created by the current cycle.
+    profile_type: Option<String>,
+    /// Size of the profile uploaded by the current cycle before
+    /// compression, 0 when none was.
+    uploaded_bytes: usize,
+    /// Initial capacity of the buffer the next profile is compressed into.
+    gzip_capacity: usize,
+    /// When the data of the profile being uploaded was collected.
+    collected_at: Instant,
+    max_upload_delay: Option<Duration>,
+    customize_create_request: Option<CreateRequestHook>,
+    pool_idle_timeout: Option<Duration>,
+    pool_max_idle_per_host: Option<usize>,
+    /// Cached client and when it was built.
+    http: Option<(hyper::Client<HttpsConnector<HttpConnector>>, Instant)>,
+    /// Base URL of the API, global or regional.
+    endpoint: String,
+    client_max_age: Option<Duration>,
+    connector_attempts: u32,
+    should_upload: Option<UploadFilter>,
+    profile_annotations: Option<AnnotationProvider>,
+    /// Directory and retention of the local archive of uploaded profiles.
+    archive: Option<(std::path::PathBuf, Duration)>,
+    on_event: Option<EventHook>,
+    #[cfg(feature = "speedscope")]
+    speedscope_dir: Option<std::path::PathBuf>,
+}
+
+impl GcpClient {
+    /// Passes `event` to the `on_event` hook, if any.
+    fn emit(&self, event: ProfilerEvent) {
+        if let Some(on_event) = &self.on_event {
+            on_event(&event);
+        }
+    }
+}
+
+async fn get_hub(
+    client: &mut GcpClient,
+) -> Result<CloudProfiler<HttpsConnector<HttpConnector>>, GcpCloudProfilingError> {
+    // Auth: Re-fetch auth token on every loop just incase we are
+    //       using GCP Metadata server to get the token.
+    let token = match &client.token_provider {
+        Some(token_provider) => {
+            token_provider().map_err(GcpCloudProfilingError::FailedToGetAuthToken)?
+        }
+        None => client.auth.get_auth_token().await?,
+    };
+    if let Some(max_age) = client.client_max_age {
+        if client
+            .http
+            .as_ref()
+            .is_some_and(|(_, created)| created.elapsed() >= max_age)
+        {
+            log::debug!(
+                target: log_target(),
+                "[gcp cloud profiler] [cycle {}] HTTP client is older than {:?}, rebuilding it",
+                client.cycle,
+                max_age
+            );
+            client.http = None;
+        }
+    }
+    // Create client for communicating with GCP profiler server, reused
+    // across calls so idle connections can be pooled
+    let http = match &client.http {
+        Some((http, _)) => http.clone(),
+        None => {
+            let connector = build_connector(client.http2_only, client.connector_attempts).await?;
+            let mut http = hyper::Client::builder();
+            http.http2_only(client.http2_only);
+            if let Some(timeout) = client.pool_idle_timeout {
+                http.pool_idle_timeout(timeout);
+            }
+            if let Some(max_idle) = client.pool_max_idle_per_host {
+                http.pool_max_idle_per_host(max_idle);
+            }
+            let http = http.build(connector);
+            client.http = Some((http.clone(), Instant::now()));
+            http
+        }
+    };
+    let mut hub = CloudProfiler::new(http, token);
+    hub.base_url(client.endpoint.clone());
+    hub.root_url(client.endpoint.clone());
+    Ok(hub)
+}
+
+/// Builds the TLS connector, trying up to `attempts` times a second apart.
+/// Loading the platform's root certificates can fail transiently, e.g. while
+/// a container's cert store is still being populated.
+async fn build_connector(
+    http2_only: bool,
+    attempts: u32,
+) -> Result<HttpsConnector<HttpConnector>, GcpCloudProfilingError> {
+    let mut attempt = 1;
+    loop {
+        let error = match native_tls_config() {
+            Ok(tls_config) => {
+                let builder =
+                    hyper_rustls::HttpsConnectorBuilder::new().with_tls_config(tls_config);
+                let connector = if http2_only {
+                    // Only offer h2 through ALPN, plain text connections are refused
+                    builder.https_only().enable_http2().build()
+                } else {
+                    builder.https_or_http().enable_http1().build()
+                };
+                return Ok(connector);
+            }
+            Err(error) => error,
+        };
+        if attempt >= attempts {
+            return Err(GcpCloudProfilingError::FailedToBuildHttpClient(error));
+        }
+        log::warn!(
+            target: log_target(),
+            "[gcp cloud profiler] Failed to build the TLS connector (attempt {} of {}): {}, retrying...",
+            attempt,
+            attempts,
+            error
+        );
+        attempt += 1;
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+/// The TLS configuration trusting the platform's root certificates. Loaded
+/// here rather than through hyper-rustls' `with_native_roots`, which panics
+/// when they can't be loaded.
+fn native_tls_config() -> Result<rustls::ClientConfig, String> {
+    let certs = rustls_native_certs::load_native_certs()
+        .map_err(|e| format!("could not load platform certs: {}", e))?;
+    let certs: Vec<Vec<u8>> = certs.into_iter().map(|cert| cert.0).collect();
+    let mut roots = rustls::RootCertStore::empty();
+    // Certificates rustls can't parse are skipped
+    roots.add_parsable_certificates(&certs);
+    if roots.is_empty() {
+        return Err("no CA certificates found".to_string());
+    }
+    Ok(rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth())
 }
 
 async fn create_profile(
+    client: &mut GcpClient,
+    parent: &str,
     deployment: &Option<Deployment>,
+    requested_profile_types: &[ProfileType],
 ) -> Result<Profile, GcpCloudProfilingError> {
-    let request = CreateProfileRequest {
+    let mut profile_types = Vec::with_capacity(requested_profile_types.len());
+    for profile_type in requested_profile_types {
+        let profile_type = profile_type.as_str().trim();
+        if profile_type.is_empty() {
+            return Err(GcpCloudProfilingError::FailedToCreateProfile(
+                "Profile types must not be empty...".to_string(),
+            ));
+        }
+        profile_types.push(profile_type.to_string());
+    }
+    let mut request = CreateProfileRequest {
         deployment: deployment.clone(),
-        profile_type: Some(vec!["Wall".to_string()]),
+        profile_type: Some(profile_types),
     };
-    match get_hub()
-        .await?
-        .projects()
-        .profiles_create(request, "projects/statsig-services")
-        .doit()
-        .await
-    {
-        Ok((_response, profile)) => Ok(profile),
+    if let Some(customize) = &client.customize_create_request {
+        customize(&mut request);
+    }
+    let hub = get_hub(client).await?;
+    // Includes the time the server holds the request until it wants a
+    // profile, not only the network round trip
+    let started = Instant::now();
+    let result = hub.projects().profiles_create(request, parent).doit().await;
+    match result {
+        Ok((response, profile)) => {
+            // Response headers carry the request id and quota information
+            // useful when filing support tickets with Google
+            log::debug!(
+                target: log_target(),
+                "[gcp cloud profiler] [cycle {}] Created profile {:?} in {:?}: status {}, headers {:?}",
+                client.cycle,
+                profile.name,
+                started.elapsed(),
+                response.status(),
+                response.headers()
+            );
+            client.emit(ProfilerEvent::ProfileCreated {
+                name: profile.name.clone().unwrap_or_default(),
+                requested_duration: profile.duration.and_then(|d| d.to_std().ok()),
+            });
+            Ok(profile)
+        }
         Err(e) => Err(GcpCloudProfilingError::FailedToCreateProfile(e.to_string())),
     }
 }
 
+/// pprof's sampler is process-global and only one `ProfilerGuard` may exist at
+/// a time. Cycles are sequential within a profiling loop; this lock extends
+/// that to every loop in the process so a cycle never starts collecting until
+/// the previous guard has been dropped (completed or cancelled).
+static PROFILER_GUARD_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// Number of profiling loops running in the process, see `RunningLoop`.
+static RUNNING_LOOPS: AtomicUsize = AtomicUsize::new(0);
+
+/// Counts a profiling loop in `RUNNING_LOOPS` until dropped.
+struct RunningLoop;
+
+impl RunningLoop {
+    fn enter() -> Self {
+        RUNNING_LOOPS.fetch_add(1, Ordering::Relaxed);
+        RunningLoop
+    }
+}
+
+impl Drop for RunningLoop {
+    fn drop(&mut self) {
+        RUNNING_LOOPS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Id of the process the first profiler was started in. A child forked from
+/// it inherits pprof's `SIGPROF` handler and sampler state, but not its
+/// interval timer or the threads backing the runtime, so profiles collected
+/// in the child would be corrupt or crash it. Profiling is disabled in a
+/// process whose id differs from this one.
+static PROFILER_PID: OnceLock<u32> = OnceLock::new();
+
+fn is_forked_child() -> bool {
+    *PROFILER_PID.get_or_init(std::process::id) != std::process::id()
+}
+
+/// Collects `profile_windows` consecutive windows of `profile_duration` and
+/// merges them into a single report.
+async fn collect_windows(
+    profile_duration: Duration,
+    configuration: &CloudProfilerConfiguration,
+    cycle: u64,
+) -> Result<Report, GcpCloudProfilingError> {
+    let mut report = do_profile(profile_duration, configuration, cycle).await?;
+    for _ in 1..configuration.profile_windows {
+        let window = do_profile(profile_duration, configuration, cycle).await?;
+        merge_report(&mut report, window);
+    }
+    Ok(report)
+}
+
+/// Adds the samples of `window` to `report`, summing the counts of samples
+/// with the same stack and thread, and extends its duration. The merged
+/// report starts with the earlier of the two, e.g. the samples collected
+/// while waiting for the server, so it doesn't end in the future.
+fn merge_report(report: &mut Report, window: Report) {
+    // `Frames` also hashes and compares its `sample_timestamp`, so the same
+    // stack from another window would never match. Merge with the timestamp
+    // cleared, keeping the earliest one aside
+    let mut merged: HashMap<pprof::Frames, (SystemTime, isize)> = HashMap::new();
+    for (mut frames, count) in report.data.drain().chain(window.data) {
+        let sampled_at = std::mem::replace(&mut frames.sample_timestamp, SystemTime::UNIX_EPOCH);
+        let (timestamp, total) = merged.entry(frames).or_insert((sampled_at, 0));
+        *timestamp = (*timestamp).min(sampled_at);
+        *total += count;
+    }
+    report.data = merged
+        .into_iter()
+        .map(|(mut frames, (sampled_at, count))| {
+            frames.sample_timestamp = sampled_at;
+            (frames, count)
+        })
+        .collect();
+    report.timing.start_time = report.timing.start_time.min(window.timing.start_time);
+    report.timing.duration += window.timing.duration;
+}
+
+/// Collects a profile for `profile_duration`. The guarded window runs on a
+/// blocking thread, so the profiler neither schedules work on the runtime it
+/// is measuring nor overruns the duration when that runtime is busy.
 async fn do_profile(
     profile_duration: Duration,
     configuration: &CloudProfilerConfiguration,
+    cycle: u64,
 ) -> Result<Report, GcpCloudProfilingError> {
-    let guard = match pprof::ProfilerGuard::new(configuration.sampling_rate) {
-        // Make sampling rate configurable
-        Ok(guard) => guard,
-        Err(e) => {
-            return Err(GcpCloudProfilingError::FailedToProfileApplication(
-                e.to_string(),
-            ));
+    // Dropped along with this future, which cuts the window short so a
+    // cancelled cycle doesn't keep the blocking thread (and the runtime's
+    // shutdown) waiting
+    let (_cancel, cancelled) = std::sync::mpsc::channel::<()>();
+    do_profile_until(Some(profile_duration), configuration, cycle, cancelled).await
+}
+
+/// Like `do_profile`, but the window also ends as soon as every sender of
+/// `cancelled` has been dropped, keeping the samples collected until then.
+/// Without a `profile_duration`, only then.
+async fn do_profile_until(
+    profile_duration: Option<Duration>,
+    configuration: &CloudProfilerConfiguration,
+    cycle: u64,
+    cancelled: std::sync::mpsc::Receiver<()>,
+) -> Result<Report, GcpCloudProfilingError> {
+    // `get_configuration` is called every cycle, so check the values in
+    // use rather than relying on validation at startup
+    configuration
+        .validate()
+        .map_err(GcpCloudProfilingError::InvalidConfiguration)?;
+    // Moved onto the blocking thread, so the lock is held until the guard is
+    // dropped there even if this future is cancelled
+    let guard_lock = PROFILER_GUARD_LOCK.lock().await;
+    let sampling_rate = configuration.sampling_rate;
+    let failed = move |reason: String| GcpCloudProfilingError::FailedToProfileApplication {
+        reason,
+        sampling_rate: Some(sampling_rate),
+        duration: profile_duration,
+    };
+    if is_forked_child() {
+        return Err(failed(
+            "Profiling is disabled in forked child processes".to_string(),
+        ));
+    }
+    let overhead_warning_percent = configuration.overhead_warning_percent;
+    let thread_allowlist = configuration.thread_allowlist.clone();
+    let collect = tokio::task::spawn_blocking(move || {
+        let _guard_lock = guard_lock;
+        let cpu_started = thread_cpu_time();
+        let guard = new_guard(sampling_rate, profile_duration)?;
+        let started = Instant::now();
+        match profile_duration {
+            Some(profile_duration) => {
+                let _ = cancelled.recv_timeout(profile_duration);
+            }
+            None => {
+                let _ = cancelled.recv();
+            }
         }
+        log::debug!(
+            target: log_target(),
+            "[gcp cloud profiler] [cycle {}] Collected profile for {:?}, requested {}",
+            cycle,
+            started.elapsed(),
+            profile_duration.map_or("until stopped".to_string(), |d| format!("{:?}", d))
+        );
+        let report = build_report(&guard, cycle).map(|mut report| {
+            if !thread_allowlist.is_empty() {
+                report.data.retain(|frames, _| {
+                    let thread = frames.thread_name_or_id();
+                    thread_allowlist
+                        .iter()
+                        .any(|prefix| thread.starts_with(prefix.as_str()))
+                });
+            }
+            report
+        });
+        drop(guard);
+        report_overhead(
+            thread_cpu_time().saturating_sub(cpu_started),
+            started.elapsed(),
+            overhead_warning_percent,
+            cycle,
+        );
+        report
+    });
+    let report = match collect.await {
+        Ok(report) => report?,
+        Err(e) => return Err(failed(e.to_string())),
     };
-    tokio::time::sleep(profile_duration).await;
+    warn_if_unsymbolized(&report);
+    Ok(report)
+}
+
+/// Starts pprof's sampler at `sampling_rate` Hz for a window of
+/// `duration`, if bounded. The sampler is process-global, so this fails with
+/// `ProfilerAlreadyRunning` while another guard, e.g. one created by the
+/// application itself, is still alive.
+fn new_guard(
+    sampling_rate: i32,
+    duration: Option<Duration>,
+) -> Result<pprof::ProfilerGuard<'static>, GcpCloudProfilingError> {
+    pprof::ProfilerGuard::new(sampling_rate).map_err(|e| match e {
+        pprof::Error::Running => GcpCloudProfilingError::ProfilerAlreadyRunning,
+        e => GcpCloudProfilingError::FailedToProfileApplication {
+            reason: e.to_string(),
+            sampling_rate: Some(sampling_rate),
+            duration,
+        },
+    })
+}
+
+/// Builds the report of `guard`, retrying once. Besides a profiler that
+/// failed to start, which fails again, building only fails reading back the
+/// samples the collector spilled to its temporary file, e.g. when the
+/// process is out of file descriptors. The samples stay with the guard until
+/// it is dropped, so a second read can succeed.
+fn build_report(
+    guard: &pprof::ProfilerGuard<'_>,
+    cycle: u64,
+) -> Result<Report, GcpCloudProfilingError> {
     guard
         .report()
         .build()
+        .or_else(|e| {
+            log::debug!(
+                target: log_target(),
+                "[gcp cloud profiler] [cycle {}] Failed to build report, retrying: {}",
+                cycle,
+                e
+            );
+            guard.report().build()
+        })
         .map_err(|e| GcpCloudProfilingError::FailedToBuildReport(e.to_string()))
 }
 
+/// CPU time consumed by the calling thread so far.
+fn thread_cpu_time() -> Duration {
+    let mut time = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: `time` is a valid, writable timespec
+    if unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut time) } != 0 {
+        return Duration::ZERO;
+    }
+    Duration::new(time.tv_sec as u64, time.tv_nsec as u32)
+}
+
+/// Logs the CPU time the collecting thread spent starting the sampler and
+/// building the report as a share of the collection window, warning above
+/// `warning_percent`. This is a rough lower bound of the profiler's cost: the
+/// `SIGPROF` handler runs on the application's own threads and is not
+/// included.
+fn report_overhead(cpu: Duration, window: Duration, warning_percent: Option<f64>, cycle: u64) {
+    let percent = 100.0 * cpu.as_secs_f64() / window.as_secs_f64().max(f64::EPSILON);
+    log::debug!(
+        target: log_target(),
+        "[gcp cloud profiler] [cycle {}] Profiler used {:?} of CPU time ({:.2}% of the window)",
+        cycle,
+        cpu,
+        percent
+    );
+    if warning_percent.is_some_and(|threshold| percent > threshold) {
+        log::warn!(
+            target: log_target(),
+            "[gcp cloud profiler] [cycle {}] Profiler overhead of {:.2}% of the window exceeds the configured threshold...",
+            cycle,
+            percent
+        );
+    }
+}
+
+/// Warns, once per process, when samples were collected but none of their
+/// frames could be resolved to a symbol, which makes the profile useless in
+/// the GCP UI.
+fn warn_if_unsymbolized(report: &Report) {
+    static WARNED: AtomicBool = AtomicBool::new(false);
+    let symbolized = report
+        .data
+        .keys()
+        .flat_map(|frames| frames.frames.iter().flatten())
+        .any(|symbol| symbol.name.is_some());
+    if report.data.is_empty() || symbolized || WARNED.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    log::warn!(
+        target: log_target(),
+        "[gcp cloud profiler] None of the collected frames could be resolved to a \
+         symbol, profiles will only show addresses. Ship the binary with its symbols (don't \
+         strip it, e.g. `strip = false` in the release profile) and build with frame pointers \
+         (`RUSTFLAGS=\"-C force-frame-pointers=yes\"`) so stacks can be walked and named..."
+    );
+}
+
+/// Uploads `report` to `profile`.
 async fn update_gcp_profile_server(
+    client: &mut GcpClient,
     report: Report,
+    profile: Profile,
+) -> Result<(), GcpCloudProfilingError> {
+    let requested = profile.profile_type.clone().unwrap_or_default();
+    let pprof_data = report_to_pprof(&report, &requested)?;
+    upload_if_accepted(
+        client,
+        &served_profile_type(&requested),
+        &pprof_data,
+        profile,
+    )
+    .await
+}
+
+/// The `ProfileType` of a profile type name returned by the server.
+fn served_profile_type(name: &str) -> ProfileType {
+    [ProfileType::Wall, ProfileType::Cpu, ProfileType::Heap]
+        .into_iter()
+        .find(|profile_type| profile_type.as_str().eq_ignore_ascii_case(name))
+        .unwrap_or_else(|| ProfileType::Other(name.to_string()))
+}
+
+/// Converts `report` to pprof, with the metadata the UI interprets its
+/// values by matching the `requested` profile type. `Report::pprof` sets
+/// the period to the sampling interval in nanoseconds but always names the
+/// time sample type and the period type `cpu`, they are renamed `wall` for
+/// wall profiles.
+fn report_to_pprof(
+    report: &Report,
+    requested: &str,
+) -> Result<pprof::protos::Profile, GcpCloudProfilingError> {
+    let mut pprof_data = report
+        .pprof()
+        .map_err(|e| GcpCloudProfilingError::FailedToSerializeProfile(e.to_string()))?;
+    if !requested.eq_ignore_ascii_case(ProfileType::Wall.as_str()) {
+        return Ok(pprof_data);
+    }
+    let strings = &mut pprof_data.string_table;
+    let Some(cpu) = strings.iter().position(|s| s == "cpu") else {
+        return Ok(pprof_data);
+    };
+    let wall = match strings.iter().position(|s| s == "wall") {
+        Some(wall) => wall,
+        None => {
+            strings.push("wall".to_string());
+            strings.len() - 1
+        }
+    };
+    let period_type = pprof_data.period_type.as_mut();
+    for value_type in pprof_data.sample_type.iter_mut().chain(period_type) {
+        if value_type.ty == cpu as i64 {
+            value_type.ty = wall as i64;
+        }
+    }
+    Ok(pprof_data)
+}
+
+/// Uploads a minimal, sample-less pprof for a cycle that was skipped, to
+/// release the profile reserved by `create_profile` on the server. No data
+/// is sent, so the release is neither archived nor reported as a
+/// `ProfileSent` event, and a failed one is not kept outstanding.
+async fn release_profile(
+    client: &mut GcpClient,
     mut profile: Profile,
 ) -> Result<(), GcpCloudProfilingError> {
-    match report.pprof() {
-        Ok(pprof_data) => {
-            // Gzip the data before sending it to GCP
-            let mut content = Vec::new();
-            if let Err(e) = pprof_data.write_to_vec(&mut content) {
-                return Err(GcpCloudProfilingError::FailedToSerializeProfile(
-                    e.to_string(),
-                ));
-            }
-            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
-            encoder.write_all(&content).unwrap();
-            let compressed_content = encoder.finish().unwrap();
-
-            // Send profile data to GCP
-            profile.profile_bytes = Some(compressed_content);
-            let name = match profile.name.clone() {
-                Some(name) => name,
-                None => {
-                    return Err(GcpCloudProfilingError::FailedToSerializeProfile(
-                        "GCP profile did not contain a name...".to_string(),
-                    ));
-                }
-            };
-            if let Err(e) = get_hub()
-                .await?
-                .projects()
-                .profiles_patch(profile, &name)
-                .doit()
-                .await
-            {
-                return Err(GcpCloudProfilingError::FailedToSendProfileToGCP(
-                    e.to_string(),
-                ));
-            }
+    let empty_pprof = pprof::protos::Profile {
+        string_table: vec!["".to_string()].into(),
+        ..Default::default()
+    };
+    let Some(name) = profile.name.clone() else {
+        return Err(GcpCloudProfilingError::FailedToSerializeProfile(
+            "GCP profile did not contain a name...".to_string(),
+        ));
+    };
+    profile.profile_bytes = Some(gzip(&serialize(&empty_pprof)?, 0)?);
+    patch_profile(client, profile, &name, Instant::now()).await
+}
+
+/// Uploads `pprof_data` unless the `should_upload` hook vetoes it, in which
+/// case the profile reserved on the server is released and the upload fails
+/// with `GcpCloudProfilingError::UploadSkipped`.
+async fn upload_if_accepted(
+    client: &mut GcpClient,
+    profile_type: &ProfileType,
+    pprof_data: &pprof::protos::Profile,
+    profile: Profile,
+) -> Result<(), GcpCloudProfilingError> {
+    let Some(should_upload) = client.should_upload.clone() else {
+        return upload_pprof(client, pprof_data, profile).await;
+    };
+    let content = serialize(pprof_data)?;
+    let compressed = compress(client, &content)?;
+    if !should_upload(profile_type, &profile, &compressed) {
+        log::debug!(
+            target: log_target(),
+            "[gcp cloud profiler] [cycle {}] Skipping upload of {:?}, vetoed by should_upload",
+            client.cycle,
+            profile.name
+        );
+        let name = profile.name.clone().unwrap_or_default();
+        if let Err(e) = release_profile(client, profile).await {
+            log::error!(
+                target: log_target(),
+                "[gcp cloud profiler] [cycle {}] Error releasing vetoed profile: {:?}",
+                client.cycle,
+                e
+            );
         }
-        Err(e) => {
+        let reason = "vetoed by should_upload".to_string();
+        client.emit(ProfilerEvent::ProfileSkipped {
+            profile: name,
+            reason: reason.clone(),
+        });
+        return Err(GcpCloudProfilingError::UploadSkipped(reason));
+    }
+    client.uploaded_bytes = content.len();
+    send_profile(client, compressed, profile).await
+}
+
+async fn upload_pprof(
+    client: &mut GcpClient,
+    pprof_data: &pprof::protos::Profile,
+    profile: Profile,
+) -> Result<(), GcpCloudProfilingError> {
+    let content = serialize(pprof_data)?;
+    upload_profile_bytes(client, &content, profile).await
+}
+
+/// Uploads an uncompressed, serialized pprof `profile.proto` for `profile`,
+/// see `send_profile`.
+async fn upload_profile_bytes(
+    client: &mut GcpClient,
+    content: &[u8],
+    profile: Profile,
+) -> Result<(), GcpCloudProfilingError> {
+    let compressed = compress(client, content)?;
+    client.uploaded_bytes = content.len();
+    send_profile(client, compressed, profile).await
+}
+
+/// Gzips a serialized pprof `profile.proto` for upload.
+fn compress(client: &mut GcpClient, content: &[u8]) -> Result<Vec<u8>, GcpCloudProfilingError> {
+    let compressed = gzip(content, client.gzip_capacity)?;
+    // Size the next buffer after the largest profile so far. Following the
+    // last one instead would let tiny, empty profiles shrink it, regrowing
+    // it for the next real profile
+    client.gzip_capacity = client.gzip_capacity.max(compressed.len());
+    Ok(compressed)
+}
+
+/// Uploads the gzip compressed pprof `compressed` for `profile`, and also
+/// writes it to the local archive, if any. Either destination failing
+/// doesn't prevent the other from receiving it.
+///
+/// The upload is buffered in memory: `profiles.patch` takes the profile as
+/// a base64 encoded `profileBytes` field of a JSON body, and neither the API
+/// nor `google-cloudprofiler2` offers a media, streaming or resumable upload
+/// to feed the gzip stream through incrementally.
+async fn send_profile(
+    client: &mut GcpClient,
+    compressed: Vec<u8>,
+    mut profile: Profile,
+) -> Result<(), GcpCloudProfilingError> {
+    profile.profile_bytes = Some(compressed);
+    if let Some(annotations) = &client.profile_annotations {
+        profile
+            .labels
+            .get_or_insert_with(HashMap::new)
+            .extend(annotations());
+    }
+    let name = match profile.name.clone() {
+        Some(name) => name,
+        None => {
             return Err(GcpCloudProfilingError::FailedToSerializeProfile(
-                e.to_string(),
+                "GCP profile did not contain a name...".to_string(),
             ));
         }
+    };
+    archive_profile(
+        client,
+        &name,
+        profile.profile_bytes.as_deref().unwrap_or_default(),
+    );
+    let collected_at = client.collected_at;
+    let result = patch_profile(client, profile.clone(), &name, collected_at).await;
+    client.emit(match &result {
+        Err(GcpCloudProfilingError::UploadSkipped(reason)) => ProfilerEvent::ProfileSkipped {
+            profile: name.clone(),
+            reason: reason.clone(),
+        },
+        _ => ProfilerEvent::ProfileSent {
+            sink: "gcp".to_string(),
+            profile: name.clone(),
+            error: result.as_ref().err().map(|e| e.to_string()),
+        },
+    });
+    let failed =
+        matches!(&result, Err(e) if !matches!(e, GcpCloudProfilingError::UploadSkipped(_)));
+    if failed && client.max_outstanding > 0 {
+        // Keep the reservation to complete it on the next cycle rather
+        // than leaving it dangling
+        if client.outstanding.len() >= client.max_outstanding {
+            if let Some((dropped, _, _)) = client.outstanding.pop_front() {
+                log::warn!(
+                    target: log_target(),
+                    "[gcp cloud profiler] [cycle {}] Dropping outstanding profile {}...",
+                    client.cycle,
+                    dropped
+                );
+            }
+        }
+        client.outstanding.push_back((name, profile, collected_at));
+    }
+    result
+}
+
+/// Writes the gzip compressed pprof `compressed` of the profile `name` to
+/// the archive directory, if any. Failures are logged and reported through
+/// the event hook only.
+fn archive_profile(client: &GcpClient, name: &str, compressed: &[u8]) {
+    let Some((dir, retention)) = &client.archive else {
+        return;
+    };
+    let id = name.rsplit('/').next().unwrap_or_default();
+    let result = archive::write_profile(dir, id, compressed, *retention);
+    match &result {
+        Ok(path) => log::debug!(
+            target: log_target(),
+            "[gcp cloud profiler] [cycle {}] Archived profile to {}",
+            client.cycle,
+            path.display()
+        ),
+        Err(e) => log::error!(
+            target: log_target(),
+            "[gcp cloud profiler] [cycle {}] Error archiving profile {}: {:?}",
+            client.cycle,
+            name,
+            e
+        ),
+    }
+    client.emit(ProfilerEvent::ProfileSent {
+        sink: "archive".to_string(),
+        profile: name.to_string(),
+        error: result.err().map(|e| e.to_string()),
+    });
+}
+
+/// Serializes `pprof_data` to a pprof `profile.proto`.
+fn serialize(pprof_data: &pprof::protos::Profile) -> Result<Vec<u8>, GcpCloudProfilingError> {
+    pprof_data
+        .write_to_bytes()
+        .map_err(|e| GcpCloudProfilingError::FailedToSerializeProfile(e.to_string()))
+}
+
+/// Gzips a serialized pprof `profile.proto` for upload. `profile_bytes` is
+/// documented as a gzip compressed serialized pprof proto and is sent base64
+/// encoded inside the JSON body, so there is no content encoding to
+/// negotiate: other algorithms (e.g. zstd) are not accepted by the API.
+/// The output buffer starts with `capacity` bytes.
+fn gzip(content: &[u8], capacity: usize) -> Result<Vec<u8>, GcpCloudProfilingError> {
+    let mut encoder = GzEncoder::new(Vec::with_capacity(capacity), Compression::default());
+    encoder
+        .write_all(content)
+        .and_then(|()| encoder.finish())
+        .map_err(|e| GcpCloudProfilingError::FailedToSerializeProfile(e.to_string()))
+}
+
+/// Process-wide limit on concurrent `profiles.patch` calls, shared by every
+/// profiling loop so their uploads can't collectively trip rate limits.
+static UPLOAD_CONCURRENCY: ConcurrencyLimit =
+    ConcurrencyLimit::new("upload_concurrency", builder::DEFAULT_UPLOAD_CONCURRENCY);
+
+/// Retries the upload of the oldest profile whose upload failed, returning
+/// `None` when there is none. A profile is retried only once.
+async fn complete_outstanding(
+    client: &mut GcpClient,
+) -> Option<Result<String, GcpCloudProfilingError>> {
+    let (name, profile, collected_at) = client.outstanding.pop_front()?;
+    Some(
+        patch_profile(client, profile, &name, collected_at)
+            .await
+            .map(|()| name),
+    )
+}
+
+/// Uploads `profile`, unless its data, collected at `collected_at`, is
+/// older than `max_upload_delay`, in which case it fails with
+/// `GcpCloudProfilingError::UploadSkipped`.
+async fn patch_profile(
+    client: &mut GcpClient,
+    profile: Profile,
+    name: &str,
+    collected_at: Instant,
+) -> Result<(), GcpCloudProfilingError> {
+    if let Some(max_delay) = client.max_upload_delay {
+        let age = collected_at.elapsed();
+        if age > max_delay {
+            client.uploaded_bytes = 0;
+            log::debug!(
+                target: log_target(),
+                "[gcp cloud profiler] [cycle {}] Skipping upload of {}, collected {:?} ago",
+                client.cycle,
+                name,
+                age
+            );
+            return Err(GcpCloudProfilingError::UploadSkipped(format!(
+                "collected {:?} ago, over the max upload delay of {:?}",
+                age, max_delay
+            )));
+        }
+    }
+    let hub = get_hub(client).await?;
+    let _permit = UPLOAD_CONCURRENCY.acquire().await;
+    let started = Instant::now();
+    let result = hub.projects().profiles_patch(profile, name).doit().await;
+    log::debug!(
+        target: log_target(),
+        "[gcp cloud profiler] [cycle {}] profiles_patch for {} took {:?}",
+        client.cycle,
+        name,
+        started.elapsed()
+    );
+    if let Err(e) = result {
+        return Err(GcpCloudProfilingError::FailedToSendProfileToGCP(
+            e.to_string(),
+        ));
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Serves every request to the returned local URL with a wall profile
+    /// of `duration`, standing in for the Cloud Profiler API: creating a
+    /// profile hands it out at once, and uploading to it succeeds.
+    async fn profiler_server(duration: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buf = [0; 4096];
+                    loop {
+                        // Read the headers and then the body of the next
+                        // request on the connection
+                        let headers_end = loop {
+                            if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                                break end;
+                            }
+                            match stream.read(&mut buf).await {
+                                Ok(0) | Err(_) => return,
+                                Ok(n) => request.extend_from_slice(&buf[..n]),
+                            }
+                        };
+                        let length = String::from_utf8_lossy(&request[..headers_end])
+                            .lines()
+                            .find_map(|line| {
+                                let (name, value) = line.split_once(':')?;
+                                name.eq_ignore_ascii_case("content-length")
+                                    .then(|| value.trim().parse::<usize>().ok())?
+                            })
+                            .unwrap_or(0);
+                        let body_end = headers_end + 4 + length;
+                        while request.len() < body_end {
+                            match stream.read(&mut buf).await {
+                                Ok(0) | Err(_) => return,
+                                Ok(n) => request.extend_from_slice(&buf[..n]),
+                            }
+                        }
+                        request.drain(..body_end);
+                        let body = format!(
+                            r#"{{"name":"projects/p/profiles/1","profileType":"WALL","duration":"{}"}}"#,
+                            duration
+                        );
+                        let response = format!(
+                            "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n{}",
+                            body.len(),
+                            body
+                        );
+                        if stream.write_all(response.as_bytes()).await.is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        url
+    }
+
+    /// A client of the API at `endpoint`, authenticating with `token`.
+    fn test_client(endpoint: String, token: Result<String, String>) -> GcpClient {
+        GcpClient {
+            auth: AuthTokenProvider::new(None),
+            token_provider: Some(Arc::new(move || token.clone())),
+            http2_only: false,
+            cycle: 0,
+            outstanding: VecDeque::new(),
+            max_outstanding: 0,
+            profile_type: None,
+            uploaded_bytes: 0,
+            gzip_capacity: builder::DEFAULT_GZIP_CAPACITY,
+            collected_at: Instant::now(),
+            max_upload_delay: None,
+            customize_create_request: None,
+            pool_idle_timeout: None,
+            pool_max_idle_per_host: None,
+            http: None,
+            endpoint,
+            client_max_age: None,
+            connector_attempts: 1,
+            should_upload: None,
+            profile_annotations: None,
+            archive: None,
+            on_event: None,
+            #[cfg(feature = "speedscope")]
+            speedscope_dir: None,
+        }
+    }
+
+    /// Runs `cycles` cycles of a profiling loop for `client`, waiting out
+    /// the back off after failed ones, and returns how many uploaded.
+    async fn drive_loop(client: &mut GcpClient, backoff: &mut LoopBackoff, cycles: u64) -> u64 {
+        let configuration = CloudProfilerConfiguration::default();
+        let mut uploaded = 0;
+        for cycle in 1..=cycles {
+            client.cycle = cycle;
+            if let Some(rbo) = backoff.retry_back_off {
+                tokio::time::sleep(Duration::from_secs_f64(rbo)).await;
+            }
+            let result = run_one_cycle(
+                client,
+                "projects/p",
+                &None,
+                &configuration,
+                None,
+                None,
+                false,
+            )
+            .await;
+            match result {
+                Ok(_) => {
+                    uploaded += 1;
+                    backoff.on_success(cycle);
+                }
+                Err(GcpCloudProfilingError::UploadSkipped(_)) => backoff.on_skipped(),
+                Err(_) => {
+                    backoff.on_failure(cycle);
+                }
+            }
+        }
+        uploaded
+    }
+
+    #[tokio::test]
+    async fn concurrent_loops_never_overlap_their_windows() {
+        let configuration = CloudProfilerConfiguration::default();
+        let window = Duration::from_millis(200);
+        let (first, second) = tokio::join!(
+            do_profile(window, &configuration, 1),
+            do_profile(window, &configuration, 1),
+        );
+        let mut timings = [first.unwrap().timing, second.unwrap().timing];
+        timings.sort_by_key(|timing| timing.start_time);
+        assert!(timings[0].start_time + timings[0].duration <= timings[1].start_time);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failing_deployment_backs_off_without_slowing_the_other() {
+        let endpoint = profiler_server("0.020s").await;
+        let mut failing = test_client(endpoint.clone(), Err("revoked".to_string()));
+        let mut healthy = test_client(endpoint, Ok("token".to_string()));
+        let mut failing_backoff = LoopBackoff::new(None, BackoffReset::Reset, 3);
+        let mut healthy_backoff = LoopBackoff::new(None, BackoffReset::Reset, 3);
+        let (failed_uploads, healthy_uploads) = tokio::join!(
+            drive_loop(&mut failing, &mut failing_backoff, 5),
+            drive_loop(&mut healthy, &mut healthy_backoff, 5),
+        );
+
+        assert_eq!(failed_uploads, 0);
+        assert_eq!(failing_backoff.consecutive_failures, 5);
+        // Degraded after 3 failures, doubling to a cycle per 4 minutes
+        assert!(failing_backoff.retry_back_off.unwrap() >= 4.0 * BACKOFF_MIN_SECS);
+        assert_eq!(healthy_uploads, 5);
+        assert_eq!(healthy_backoff.consecutive_failures, 0);
+        assert_eq!(healthy_backoff.retry_back_off, None);
+    }
+
+    #[tokio::test]
+    async fn vetoed_profile_is_released_without_being_sent() {
+        let mut client = test_client(profiler_server("0.020s").await, Ok("token".to_string()));
+        client.max_outstanding = 1;
+        client.should_upload = Some(Arc::new(|_, _, _| false));
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = events.clone();
+        client.on_event = Some(Arc::new(move |event: &ProfilerEvent| {
+            let kind = match event {
+                ProfilerEvent::BackingOff { .. } => "backing off",
+                ProfilerEvent::ProfileCreated { .. } => "created",
+                ProfilerEvent::ProfileSent { .. } => "sent",
+                ProfilerEvent::ProfileSkipped { .. } => "skipped",
+            };
+            recorded.lock().unwrap().push(kind);
+        }));
+        let result = run_one_cycle(
+            &mut client,
+            "projects/p",
+            &None,
+            &CloudProfilerConfiguration::default(),
+            None,
+            None,
+            false,
+        )
+        .await;
+
+        assert!(matches!(
+            result,
+            Err(GcpCloudProfilingError::UploadSkipped(_))
+        ));
+        assert_eq!(*events.lock().unwrap(), ["created", "skipped"]);
+        assert!(client.outstanding.is_empty());
+        assert_eq!(client.uploaded_bytes, 0);
+    }
+
+    #[tokio::test]
+    async fn sampled_out_cycle_is_skipped_and_uploads_nothing() {
+        let mut client = test_client(profiler_server("0.020s").await, Ok("token".to_string()));
+        let configuration = CloudProfilerConfiguration {
+            cycle_sampling_probability: 0.0,
+            ..Default::default()
+        };
+        let result = run_one_cycle(
+            &mut client,
+            "projects/p",
+            &None,
+            &configuration,
+            None,
+            None,
+            false,
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(GcpCloudProfilingError::UploadSkipped(_))
+        ));
+        assert_eq!(client.uploaded_bytes, 0);
+
+        // Unless the cycle was requested
+        let result = run_one_cycle(
+            &mut client,
+            "projects/p",
+            &None,
+            &configuration,
+            None,
+            None,
+            true,
+        )
+        .await;
+        assert_eq!(result.unwrap(), "projects/p/profiles/1");
+        assert!(client.uploaded_bytes > 0);
+    }
+
+    #[tokio::test]
+    async fn skipped_empty_profile_is_not_reported_uploaded() {
+        let mut client = test_client(profiler_server("0.020s").await, Ok("token".to_string()));
+        let configuration = CloudProfilerConfiguration {
+            on_empty_profile: OnEmptyProfile::Skip,
+            // Filters out every sample
+            thread_allowlist: vec!["no-such-thread".to_string()],
+            ..Default::default()
+        };
+        let result = run_one_cycle(
+            &mut client,
+            "projects/p",
+            &None,
+            &configuration,
+            None,
+            None,
+            true,
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(GcpCloudProfilingError::UploadSkipped(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn window_does_not_overrun_on_a_busy_runtime() {
+        let configuration = CloudProfilerConfiguration::default();
+        let window = Duration::from_millis(100);
+        let busy = async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            // Blocks the only thread of the runtime
+            std::thread::sleep(Duration::from_millis(500));
+        };
+        let (report, ()) = tokio::join!(do_profile(window, &configuration, 1), busy);
+        // A window timed on the runtime would only end once it is free again
+        let overrun = report.unwrap().timing.duration.saturating_sub(window);
+        assert!(
+            overrun < Duration::from_millis(200),
+            "overran by {:?}",
+            overrun
+        );
+    }
+
+    #[tokio::test]
+    async fn cancelled_cycle_drops_its_guard_before_the_next_one_starts() {
+        let configuration = CloudProfilerConfiguration::default();
+        let cancelled = tokio::time::timeout(
+            Duration::from_millis(50),
+            do_profile(Duration::from_secs(10), &configuration, 1),
+        )
+        .await;
+        assert!(cancelled.is_err());
+        // Fails with ProfilerAlreadyRunning if the cancelled guard were alive
+        do_profile(Duration::from_millis(50), &configuration, 2)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn second_guard_is_rejected_as_already_running() {
+        // Keep the other tests' cycles out while the guards are alive
+        let _guard_lock = PROFILER_GUARD_LOCK.lock().await;
+        let window = Duration::from_secs(1);
+        let first = new_guard(100, Some(window)).unwrap();
+        assert!(matches!(
+            new_guard(100, Some(window)),
+            Err(GcpCloudProfilingError::ProfilerAlreadyRunning)
+        ));
+        drop(first);
+        assert!(new_guard(100, Some(window)).is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sleep_between_cycles_runs_on_tokio_time() {
+        let (requester, mut requests) = tokio::sync::mpsc::channel(1);
+        let mut waiting = Vec::new();
+        let started = tokio::time::Instant::now();
+        sleep_or_request(Duration::from_secs(3600), &mut requests, &mut waiting).await;
+        assert_eq!(started.elapsed(), Duration::from_secs(3600));
+        assert!(waiting.is_empty());
+
+        let (request, _response) = tokio::sync::oneshot::channel();
+        requester.send(request).await.unwrap();
+        let started = tokio::time::Instant::now();
+        sleep_or_request(Duration::from_secs(3600), &mut requests, &mut waiting).await;
+        assert_eq!(started.elapsed(), Duration::ZERO);
+        assert_eq!(waiting.len(), 1);
+    }
+
+    #[test]
+    fn upload_payload_is_gzip_that_round_trips_to_the_profile() {
+        use pprof::protos::{Function, Line, Location, Sample, ValueType};
+
+        let mut profile = pprof::protos::Profile::new();
+        profile.string_table =
+            vec!["".into(), "cpu".into(), "nanoseconds".into(), "main".into()].into();
+        let mut sample_type = ValueType::new();
+        sample_type.ty = 1;
+        sample_type.unit = 2;
+        profile.sample_type.push(sample_type);
+        let mut function = Function::new();
+        function.id = 1;
+        function.name = 3;
+        profile.function.push(function);
+        let mut line = Line::new();
+        line.function_id = 1;
+        let mut location = Location::new();
+        location.id = 1;
+        location.line.push(line);
+        profile.location.push(location);
+        for value in [10, 20, 30] {
+            let mut sample = Sample::new();
+            sample.location_id = vec![1];
+            sample.value = vec![value];
+            profile.sample.push(sample);
+        }
+
+        // Also with a buffer too small for the output, which has to grow
+        for capacity in [0, builder::DEFAULT_GZIP_CAPACITY] {
+            let payload = gzip(&serialize(&profile).unwrap(), capacity).unwrap();
+            let mut content = Vec::new();
+            flate2::read::GzDecoder::new(payload.as_slice())
+                .read_to_end(&mut content)
+                .unwrap();
+            let decoded = pprof::protos::Profile::parse_from_bytes(&content).unwrap();
+            assert_eq!(decoded.sample.len(), 3);
+            assert_eq!(decoded, profile);
+        }
+    }
+
+    #[test]
+    fn pprof_metadata_matches_the_profile_type() {
+        let stack = |function: &str, thread_id| pprof::Frames {
+            frames: vec![vec![pprof::Symbol {
+                name: Some(function.as_bytes().to_vec()),
+                addr: None,
+                lineno: None,
+                filename: None,
+            }]],
+            thread_name: "worker".to_string(),
+            thread_id,
+            sample_timestamp: SystemTime::now(),
+        };
+        let mut report = Report {
+            data: Default::default(),
+            timing: Default::default(),
+        };
+        report.timing.frequency = 250;
+        report.data.insert(stack("handle_request", 1), 3);
+        report.data.insert(stack("serialize", 2), 5);
+        let name = |profile: &pprof::protos::Profile, index: i64| {
+            profile.string_table[index as usize].clone()
+        };
+
+        for (profile_type, time) in [(ProfileType::Wall, "wall"), (ProfileType::Cpu, "cpu")] {
+            let profile = report_to_pprof(&report, profile_type.as_str()).unwrap();
+            let sample_types: Vec<_> = profile
+                .sample_type
+                .iter()
+                .map(|t| (name(&profile, t.ty), name(&profile, t.unit)))
+                .collect();
+            assert_eq!(
+                sample_types,
+                [
+                    ("samples".to_string(), "count".to_string()),
+                    (time.to_string(), "nanoseconds".to_string()),
+                ]
+            );
+            let period_type = profile.period_type.as_ref().unwrap();
+            assert_eq!(name(&profile, period_type.ty), time);
+            assert_eq!(name(&profile, period_type.unit), "nanoseconds");
+            assert_eq!(profile.period, 4_000_000);
+            let mut values: Vec<_> = profile.sample.iter().map(|s| s.value.clone()).collect();
+            values.sort();
+            assert_eq!(values, [vec![3, 12_000_000], vec![5, 20_000_000]]);
+        }
+    }
+
+    #[test]
+    fn merged_windows_sum_the_counts_of_a_stack() {
+        let stack = |function: &str, sampled_at| pprof::Frames {
+            frames: vec![vec![pprof::Symbol {
+                name: Some(function.as_bytes().to_vec()),
+                addr: None,
+                lineno: None,
+                filename: None,
+            }]],
+            thread_name: "worker".to_string(),
+            thread_id: 1,
+            sample_timestamp: sampled_at,
+        };
+        let window = |sampled_at, counts: &[(&str, isize)]| {
+            let mut report = Report {
+                data: Default::default(),
+                timing: Default::default(),
+            };
+            report.timing.duration = Duration::from_secs(10);
+            for (function, count) in counts {
+                report.data.insert(stack(function, sampled_at), *count);
+            }
+            report
+        };
+        let first_sampled_at = SystemTime::UNIX_EPOCH + Duration::from_secs(100);
+        let mut report = window(first_sampled_at, &[("handle_request", 3), ("serialize", 1)]);
+        merge_report(
+            &mut report,
+            window(
+                first_sampled_at + Duration::from_secs(10),
+                &[("handle_request", 4)],
+            ),
+        );
+
+        let mut counts: Vec<_> = report
+            .data
+            .iter()
+            .map(|(frames, count)| (frames.frames[0][0].name(), *count))
+            .collect();
+        counts.sort();
+        assert_eq!(
+            counts,
+            [
+                ("handle_request".to_string(), 7),
+                ("serialize".to_string(), 1)
+            ]
+        );
+        assert!(report
+            .data
+            .keys()
+            .all(|frames| frames.sample_timestamp == first_sampled_at));
+        assert_eq!(report.timing.duration, Duration::from_secs(20));
+    }
+
+    #[test]
+    fn merged_report_starts_with_the_earlier_one() {
+        let report = |start_time, duration| {
+            let mut report = Report {
+                data: Default::default(),
+                timing: Default::default(),
+            };
+            report.timing.start_time = start_time;
+            report.timing.duration = duration;
+            report
+        };
+        let waited_from = SystemTime::UNIX_EPOCH + Duration::from_secs(100);
+        // The window collected after the server answered, merged with the
+        // samples collected while waiting for it
+        let mut window = report(
+            waited_from + Duration::from_secs(50),
+            Duration::from_secs(10),
+        );
+        merge_report(&mut window, report(waited_from, Duration::from_secs(50)));
+        assert_eq!(window.timing.start_time, waited_from);
+        assert_eq!(window.timing.duration, Duration::from_secs(60));
+    }
+
+    #[tokio::test]
+    async fn uploads_never_exceed_the_concurrency_limit() {
+        // Not the process-wide limit, which the other tests' uploads share
+        static UPLOADS: ConcurrencyLimit = ConcurrencyLimit::new("upload_concurrency", 2);
+        assert_eq!(UPLOADS.init(Some(3)), 3);
+        // Fixed by the first profiler started
+        assert_eq!(UPLOADS.init(Some(5)), 3);
+        let active = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let mut uploads = tokio::task::JoinSet::new();
+        for _ in 0..8 {
+            let (active, peak) = (active.clone(), peak.clone());
+            uploads.spawn(async move {
+                let _permit = UPLOADS.acquire().await;
+                let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                active.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+        while uploads.join_next().await.is_some() {}
+        assert_eq!(peak.load(Ordering::SeqCst), 3);
+    }
+}
@@ -0,0 +1,85 @@
+//! Local export of collected reports in the speedscope file format
+//! (https://www.speedscope.app/file-format-schema.json), enabled with the
+//! `speedscope` feature.
+//!
+//! Each thread becomes a `sampled` profile whose samples are the distinct
+//! stacks of the report, weighted by their sample count converted to
+//! nanoseconds at the sampling frequency. The report is aggregated, so the
+//! order of samples carries no meaning and the time order view is not a
+//! timeline; inlined functions appear as separate frames as in the pprof
+//! output, and only the file and line of each symbol (when debug info is
+//! available) are kept.
+
+use pprof::Report;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+fn to_speedscope(report: &Report, name: &str) -> Value {
+    let nanos_per_sample = 1_000_000_000 / i64::from(report.timing.frequency.max(1));
+    let mut frames = Vec::new();
+    let mut frame_indices = HashMap::new();
+    let mut threads: HashMap<String, (Vec<Vec<usize>>, Vec<i64>)> = HashMap::new();
+    for (stack, count) in &report.data {
+        let mut sample = Vec::new();
+        // pprof stores frames leaf first, speedscope expects the root first
+        for symbol in stack.frames.iter().rev().flat_map(|f| f.iter().rev()) {
+            let key = (symbol.to_string(), symbol.filename.clone(), symbol.lineno);
+            let index = *frame_indices
+                .entry(key)
+                .or_insert_with_key(|(name, file, line)| {
+                    let mut frame = json!({ "name": name });
+                    if let Some(file) = file {
+                        frame["file"] = json!(file.to_string_lossy());
+                    }
+                    if let Some(line) = line {
+                        frame["line"] = json!(line);
+                    }
+                    frames.push(frame);
+                    frames.len() - 1
+                });
+            sample.push(index);
+        }
+        let (samples, weights) = threads.entry(stack.thread_name_or_id()).or_default();
+        samples.push(sample);
+        weights.push(*count as i64 * nanos_per_sample);
+    }
+
+    let mut threads: Vec<_> = threads.into_iter().collect();
+    threads.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let profiles: Vec<Value> = threads
+        .into_iter()
+        .map(|(thread, (samples, weights))| {
+            json!({
+                "type": "sampled",
+                "name": thread,
+                "unit": "nanoseconds",
+                "startValue": 0,
+                "endValue": weights.iter().sum::<i64>(),
+                "samples": samples,
+                "weights": weights,
+            })
+        })
+        .collect();
+    json!({
+        "$schema": "https://www.speedscope.app/file-format-schema.json",
+        "name": name,
+        "exporter": format!("cloud_profiler_rust@{}", env!("CARGO_PKG_VERSION")),
+        "shared": { "frames": frames },
+        "profiles": profiles,
+    })
+}
+
+/// Writes `report` to `<dir>/<file_name>.speedscope.json`, returning the
+/// path written.
+pub(crate) fn write_report(
+    report: &Report,
+    dir: &Path,
+    file_name: &str,
+) -> std::io::Result<PathBuf> {
+    let path = dir.join(format!("{}.speedscope.json", file_name));
+    let content = serde_json::to_vec(&to_speedscope(report, file_name))?;
+    std::fs::create_dir_all(dir)?;
+    std::fs::write(&path, content)?;
+    Ok(path)
+}
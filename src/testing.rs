@@ -0,0 +1,102 @@
+//! Helpers for downstream integration tests, enabled with the `testing`
+//! feature.
+
+use flate2::read::GzDecoder;
+use pprof::protos::Message;
+use std::collections::HashSet;
+use std::io::Read;
+
+/// Parses a pprof `profile.proto`, gzip compressed as uploaded to GCP or
+/// uncompressed, and checks basic invariants: it has samples, every sample
+/// has one value per sample type and references existing locations, every
+/// location has lines referencing existing functions, and every string
+/// table index is in bounds.
+pub fn validate_pprof(bytes: &[u8]) -> Result<(), String> {
+    let mut content = Vec::new();
+    let bytes = if bytes.starts_with(&[0x1f, 0x8b]) {
+        GzDecoder::new(bytes)
+            .read_to_end(&mut content)
+            .map_err(|e| format!("invalid gzip stream: {}", e))?;
+        &content
+    } else {
+        bytes
+    };
+    let profile = pprof::protos::Profile::parse_from_bytes(bytes)
+        .map_err(|e| format!("invalid profile.proto: {}", e))?;
+
+    let strings = profile.string_table.len() as i64;
+    let check_string = |index: i64, what: &str| {
+        if (0..strings).contains(&index) {
+            Ok(())
+        } else {
+            Err(format!(
+                "{} string index {} is out of bounds ({} strings)",
+                what, index, strings
+            ))
+        }
+    };
+    if profile.string_table.first().map(String::as_str) != Some("") {
+        return Err("string table must start with the empty string".to_string());
+    }
+    for value_type in &profile.sample_type {
+        check_string(value_type.ty, "sample type")?;
+        check_string(value_type.unit, "sample unit")?;
+    }
+    check_string(profile.default_sample_type, "default sample type")?;
+    check_string(profile.drop_frames, "drop frames")?;
+    check_string(profile.keep_frames, "keep frames")?;
+
+    let functions: HashSet<u64> = profile.function.iter().map(|f| f.id).collect();
+    for function in &profile.function {
+        check_string(function.name, "function name")?;
+        check_string(function.system_name, "function system name")?;
+        check_string(function.filename, "function filename")?;
+    }
+    let locations: HashSet<u64> = profile.location.iter().map(|l| l.id).collect();
+    for location in &profile.location {
+        if location.line.is_empty() {
+            return Err(format!("location {} has no lines", location.id));
+        }
+        if let Some(line) = location
+            .line
+            .iter()
+            .find(|line| !functions.contains(&line.function_id))
+        {
+            return Err(format!(
+                "location {} references unknown function {}",
+                location.id, line.function_id
+            ));
+        }
+    }
+
+    if profile.sample.is_empty() {
+        return Err("profile has no samples".to_string());
+    }
+    for sample in &profile.sample {
+        if sample.value.len() != profile.sample_type.len() {
+            return Err(format!(
+                "sample has {} values for {} sample types",
+                sample.value.len(),
+                profile.sample_type.len()
+            ));
+        }
+        if sample.location_id.is_empty() {
+            return Err("sample has no locations".to_string());
+        }
+        if let Some(id) = sample.location_id.iter().find(|id| !locations.contains(id)) {
+            return Err(format!("sample references unknown location {}", id));
+        }
+        for label in &sample.label {
+            check_string(label.key, "label key")?;
+            check_string(label.str, "label value")?;
+        }
+    }
+    Ok(())
+}
+
+/// Panics with the reason when `validate_pprof` rejects `bytes`.
+pub fn assert_valid_pprof(bytes: &[u8]) {
+    if let Err(e) = validate_pprof(bytes) {
+        panic!("invalid pprof profile: {}", e);
+    }
+}